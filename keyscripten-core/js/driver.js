@@ -6,10 +6,21 @@ let $$DESCRIPTIONS = [];
 let $$CALLBACKS = {};
 let $$CONFIG_SCHEMAS = {};
 let $$CONFIG = {};
+let $$OPTIONS = {};
+let $$QUARANTINED = {};
+let $$CURRENT_PLUGIN_ID = undefined;
+// wall-clock budget for each callback, timer and promise job, in milliseconds.
+// js_builtin.rs aborts the plugin calling the API after that.
+const CALLBACK_BUDGET_MS = 200;
+let $$TIMERS = [];
+let $$NEXT_TIMER_ID = 1;
 let app_config = JSON.parse($$loadAppConfigJson());
 
 // public API
-function registerPlugin(id, name, description, callback, config_schema) {
+function registerPlugin(id, name, description, callback, config_schema, options) {
+    // Throws if the schema is invalid. The defaults are filled.
+    config_schema = JSON.parse($$normalizeConfigSchema(JSON.stringify(config_schema || [])));
+
     if (! $$IDS.includes(id)) {
         $$IDS.push(id);
     }
@@ -19,20 +30,94 @@ function registerPlugin(id, name, description, callback, config_schema) {
     $$CALLBACKS[id] = callback;
     $$CONFIG_SCHEMAS[id] = config_schema;
     $$CONFIG[id] = buildConfig(id, config_schema);
+    $$OPTIONS[id] = options || {};
+    delete $$QUARANTINED[id];
+    $$resetPluginFault(id);
 
     console.log(`Registered plugin: id=${id} name=${name} config=${JSON.stringify($$CONFIG[id])}`);
 }
 
+// public API
+function setTimeout(callback, delay, ...args) {
+    const id = $$NEXT_TIMER_ID++;
+    $$TIMERS.push({
+        id: id,
+        at: Date.now() + (delay || 0),
+        callback: callback,
+        args: args,
+        // the timer runs as the plugin that set it.
+        owner: $$CURRENT_PLUGIN_ID,
+    });
+    return id;
+}
+
+// public API
+function clearTimeout(id) {
+    $$TIMERS = $$TIMERS.filter(timer => timer.id !== id);
+}
+
+// public API
+function sleep(ms) {
+    return new Promise(resolve => setTimeout(resolve, ms));
+}
+
+// The plugin code runs between enterPlugin and leavePlugin.
+const enterPlugin = function (id) {
+    $$CURRENT_PLUGIN_ID = id;
+    $$setActivePlugin(id, CALLBACK_BUDGET_MS);
+};
+
+const leavePlugin = function () {
+    $$CURRENT_PLUGIN_ID = undefined;
+    $$setActivePlugin(undefined);
+};
+
+const reportPluginError = function (id, e) {
+    // don't leave the modifier keys released by the plugin.
+    $$resyncModifiers();
+    if ($$reportPluginError(id, String(e), e && e.stack)) {
+        console.error(`Plugin ${id} is disabled, since it threw too many exceptions.`);
+        $$QUARANTINED[id] = true;
+    }
+};
+
+const faultPlugin = function (id, message) {
+    console.error(`Plugin ${id} is disabled: ${message}`);
+    $$resyncModifiers();
+    $$faultPlugin(id, message);
+    $$QUARANTINED[id] = true;
+};
+
+const trackPromise = function (id, promise) {
+    if (promise && typeof promise.then === "function") {
+        promise.then(undefined, e => {
+            console.error(`[${id}] Unhandled promise rejection: ${e}`);
+            reportPluginError(id, e);
+        });
+    }
+};
+
 const reloadConfig = function () {
+    app_config = JSON.parse($$loadAppConfigJson());
     for (const id of Object.keys($$CONFIG)) {
         $$CONFIG[id] = buildConfig(id, $$CONFIG_SCHEMAS[id]);
     }
 };
 
+const isEnabled = function (id) {
+    const plugin_config = (app_config.plugins || {})[id];
+    return !plugin_config || plugin_config.enabled !== false;
+};
+
 const buildConfig = function (id, config_schema) {
     const config = {};
+    const stored = ((app_config.plugins || {})[id] || {}).config || {};
     for (const item of config_schema) {
-        const value = (((app_config.plugins || {})[id] || {}).config || {})[item.name] || item.default;
+        // 0 and false are the valid values. "" is valid only for the text.
+        const isText = item.type === "string" || item.type === "text";
+        const missing = stored[item.name] === undefined || stored[item.name] === null
+            || (stored[item.name] === "" && !isText);
+        const value = missing ? item.default : stored[item.name];
 
         switch (item.type) {
             case "hotkey":
@@ -40,11 +125,25 @@ const buildConfig = function (id, config_schema) {
                 let hotkey = new HotKey(value);
                 config[item.name] = hotkey;
                 break;
+            case "hotkey_list":
+                config[item.name] = (Array.isArray(value) ? value : [value]).map((it) => new HotKey(it));
+                break;
             case "string":
-                config[item.name] = value;
+            case "text":
+            case "enum":
+                config[item.name] = String(value);
                 break;
             case "integer":
-                config[item.name] = parseInt(value, 10);
+                config[item.name] = typeof value === "number" ? value : parseInt(value, 10);
+                break;
+            case "float":
+                config[item.name] = typeof value === "number" ? value : parseFloat(value);
+                break;
+            case "boolean":
+                config[item.name] = value === true || value === "true";
+                break;
+            case "key":
+                config[item.name] = $$getKeycode(value);
                 break;
             default:
                 throw new Error(`Unknown type for plugin '${id}'(${item.name}): '${item.type}'`)
@@ -56,30 +155,126 @@ const buildConfig = function (id, config_schema) {
 };
 
 // called by js.rs
-function $$invokeEvent(event, needsConfigReload) {
-    if (needsConfigReload) {
-        console.log("Reloading configuration file")
-        reloadConfig();
-    }
+function $$reloadConfig() {
+    console.log("Reloading configuration file")
+    reloadConfig();
+}
 
+// called by js.rs
+function $$invokeEvent(event) {
     for (let i = 0; i < $$IDS.length; i++) {
         let id = $$IDS[i];
         let callback = $$CALLBACKS[id];
         let config = $$CONFIG[id];
 
+        if ($$QUARANTINED[id] || !isEnabled(id)) {
+            continue;
+        }
+
+        // the keyUp paired with the consumed keyDown is dropped anyway.
+        if (event.suppressed && !$$OPTIONS[id].receiveSuppressedEvents) {
+            continue;
+        }
+
         try {
+            event.waitUntil = promise => trackPromise(id, promise);
+            enterPlugin(id);
+            const started = Date.now();
             const result = callback(event, config);
+            const elapsed = Date.now() - started;
+            leavePlugin();
+            if (elapsed > CALLBACK_BUDGET_MS) {
+                faultPlugin(id, `The callback took ${elapsed}ms, exceeding the budget(${CALLBACK_BUDGET_MS}ms).`);
+                // we are late already. pass the original event through.
+                return true;
+            }
+            if (result && typeof result.then === "function") {
+                // async callbacks can't decide whether to drop the event.
+                trackPromise(id, result);
+                continue;
+            }
             if (!result) {
                 return result;
             }
         } catch (e) {
+            leavePlugin();
             console.log(`Cannot invoke the ${id}: ${e}`);
-            return true;
+            reportPluginError(id, e);
+            // other plugins still handle the event.
         }
     }
     return true;
 }
 
+// called by js.rs
+function $$runTimers() {
+    const now = Date.now();
+    const expired = $$TIMERS.filter(timer => timer.at <= now);
+    if (expired.length === 0) {
+        return;
+    }
+
+    for (const timer of expired) {
+        // removed one by one. If the timer is aborted, the rest run on the next tick.
+        $$TIMERS = $$TIMERS.filter(it => it !== timer);
+        const id = timer.owner;
+        if (id !== undefined && $$QUARANTINED[id]) {
+            continue;
+        }
+
+        try {
+            if (id !== undefined) {
+                enterPlugin(id);
+            }
+            const started = Date.now();
+            timer.callback(...timer.args);
+            const elapsed = Date.now() - started;
+            leavePlugin();
+            if (id !== undefined && elapsed > CALLBACK_BUDGET_MS) {
+                faultPlugin(id, `The timer took ${elapsed}ms, exceeding the budget(${CALLBACK_BUDGET_MS}ms).`);
+            }
+        } catch (e) {
+            leavePlugin();
+            console.error(`Cannot run timer callback: ${e}`);
+            if (id !== undefined) {
+                reportPluginError(id, e);
+            }
+        }
+    }
+}
+
+// called by js.rs, before the promise job of the plugin.
+function $$enterPlugin(id) {
+    enterPlugin(id);
+}
+
+// called by js.rs, after the promise job of the plugin.
+function $$leavePlugin() {
+    leavePlugin();
+}
+
+// called by js.rs, when the callback was aborted by the runtime limits.
+function $$faultCurrentPlugin(message) {
+    const id = $$CURRENT_PLUGIN_ID;
+    leavePlugin();
+    if (id !== undefined) {
+        faultPlugin(id, message);
+    }
+    return id;
+}
+
+// called by js.rs
+function $$setPluginEnabled(plugin_id, enabled) {
+    if (!app_config.plugins) {
+        app_config.plugins = {};
+    }
+    if (!app_config.plugins[plugin_id]) {
+        app_config.plugins[plugin_id] = {enabled: true, config: {}};
+    }
+    app_config.plugins[plugin_id].enabled = enabled;
+    console.log(`Set enabled: id=${plugin_id} enabled=${enabled}`);
+}
+
 // called by js.rs
 function $$getConfigSchema(event) {
     const result = [];
@@ -121,6 +316,9 @@ function $$unloadPlugin(plugin_id) {
     delete $$CALLBACKS[plugin_id];
     delete $$CONFIG_SCHEMAS[plugin_id];
     delete $$CONFIG[plugin_id];
+    delete $$OPTIONS[plugin_id];
+    delete $$QUARANTINED[plugin_id];
+    $$TIMERS = $$TIMERS.filter(timer => timer.owner !== plugin_id);
 
     console.log(`Unloaded plugin: id=${plugin_id}`);
 }
@@ -1,5 +1,4 @@
 (function () {
-    let latest_flags = undefined;
     const buffer = [];
 
     /**
@@ -11,28 +10,28 @@
 
         if (repeatSize !== null) {
             console.log(`DynamicMacro: repeat detected`)
-            sendFlagsChangedEvent(kCGEventFlagMaskNonCoalesced);
+            releaseAllModifiers();
 
             const front = buffer.slice(0, repeatSize);
             for (const keyState of front.reverse()) {
                 sendKeyboardEvent(keyState[0], keyState[1], true);
             }
 
-            sendFlagsChangedEvent(latest_flags);
+            sendFlagsChangedEvent(getModifierState().physical);
 
             return true;
         } else {
             const patternXYX = checkPatternXYX(buffer);
             if (patternXYX) {
                 console.log(`DynamicMacro: Predicted`)
-                sendFlagsChangedEvent(kCGEventFlagMaskNonCoalesced);
+                releaseAllModifiers();
 
                 for (const keyState of patternXYX.Y.reverse()) {
                     sendKeyboardEvent(keyState[0], keyState[1], true);
                     buffer.unshift(keyState);
                 }
 
-                sendFlagsChangedEvent(latest_flags);
+                sendFlagsChangedEvent(getModifierState().physical);
 
                 return true;
             } else {
@@ -91,19 +90,18 @@
         function (event, config) {
             // console.log(`config=${JSON.stringify(config)}`);
 
-            if (event.type === "flagsChanged") {
-                latest_flags = event.flags;
-            } else if (event.type === "keyDown") {
-                if (config.hotkey.matches(latest_flags, event.keycode)) {
+            if (event.type === "keyDown") {
+                const flags = getModifierState().physical;
+                if (config.hotkey.matches(flags, event.keycode)) {
                     return !run_dynamic_macro();
                 }
 
-                buffer.unshift([event.keycode, latest_flags]);
+                buffer.unshift([event.keycode, flags]);
                 if (buffer.length > 10) {
                     buffer.pop();
                 }
             }
-            // console.log(`event detected :::${JSON.stringify(event)}, ${latest_flags}`);
+            // console.log(`event detected :::${JSON.stringify(event)}`);
 
             return true; // send event to the normal destination
         },
@@ -118,7 +116,8 @@
                 "name": "buffer_size",
                 "type": "integer",
                 "description": "Maximum history size. This plugin consumes O(N**2) for each typing. Do not set too large buffer.",
-                "default": "64"
+                "default": 64,
+                "min": 1
             }
         ]
     );
@@ -0,0 +1,16 @@
+// keyscripten:keys - key codes, hot keys and the keyboard state.
+export const Key = globalThis.Key;
+export const HotKey = globalThis.HotKey;
+export const getModifierState = globalThis.getModifierState;
+export const isPressed = globalThis.isPressed;
+export const pressedKeys = globalThis.pressedKeys;
+
+export const kCGEventFlagMaskAlphaShift = globalThis.kCGEventFlagMaskAlphaShift;
+export const kCGEventFlagMaskShift = globalThis.kCGEventFlagMaskShift;
+export const kCGEventFlagMaskControl = globalThis.kCGEventFlagMaskControl;
+export const kCGEventFlagMaskAlternate = globalThis.kCGEventFlagMaskAlternate;
+export const kCGEventFlagMaskCommand = globalThis.kCGEventFlagMaskCommand;
+export const kCGEventFlagMaskHelp = globalThis.kCGEventFlagMaskHelp;
+export const kCGEventFlagMaskSecondaryFn = globalThis.kCGEventFlagMaskSecondaryFn;
+export const kCGEventFlagMaskNumericPad = globalThis.kCGEventFlagMaskNumericPad;
+export const kCGEventFlagMaskNonCoalesced = globalThis.kCGEventFlagMaskNonCoalesced;
@@ -0,0 +1,5 @@
+// keyscripten:send - send the events to the applications.
+export const sendKeys = globalThis.sendKeys;
+export const sendKeyboardEvent = globalThis.sendKeyboardEvent;
+export const sendFlagsChangedEvent = globalThis.sendFlagsChangedEvent;
+export const releaseAllModifiers = globalThis.releaseAllModifiers;
@@ -1,3 +1,12 @@
+// ==KeyScriptenPlugin==
+// {
+//   "id": /*ID*/{},
+//   "name": /*NAME*/{},
+//   "description": /*DESC*/{},
+//   "version": "0.1.0",
+//   "api_version": 1
+// }
+// ==/KeyScriptenPlugin==
 (function () {
     const id = /*ID*/{};
     let latestFlags = undefined;
@@ -30,7 +39,8 @@
                 "name": "size",
                     "type": "integer",
                     "description": "Size of something.",
-                    "default": "64"
+                    "default": 64,
+                    "min": 1
             }
         ]
     )
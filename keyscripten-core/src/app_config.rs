@@ -1,23 +1,63 @@
+use crate::config_migration::{self, CONFIG_VERSION};
+use crate::file_security;
 use crate::APP_NAME;
+use anyhow::anyhow;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::fs::{File, OpenOptions};
+use std::fs::File;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+// The event passes through, if the plugins don't decide by this time.
+const DEFAULT_EVENT_DEADLINE_MS: u64 = 50;
+
+// `config.json.1` is the newest backup, `config.json.5` is the oldest one.
+const CONFIG_BACKUPS: usize = 5;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct AppConfig {
+    // See `config_migration`. Missing in the files written before the versioning.
+    #[serde(default)]
+    pub version: u64,
     // key is the plugin id.
     // values are map of configurations.
     pub plugins: Option<HashMap<String, PluginConfig>>,
     pub log_level: String,
+    // in milliseconds
+    pub event_deadline_ms: Option<u64>,
+    // Hotkey to switch to the passthrough mode, e.g. `C-S-M-escape`.
+    pub panic_chord: Option<String>,
+    // Hotkey to pause/resume the plugins, e.g. `C-S-M-p`.
+    pub pause_hotkey: Option<String>,
+    // Restore the pause state on the next launch.
+    pub remember_pause_state: Option<bool>,
+    pub paused: Option<bool>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PluginConfig {
     pub enabled: bool,
-    pub config: Option<HashMap<String, String>>,
+    // The values are checked by the plugin's `config_schema` on save. See `config_schema`.
+    pub config: Option<HashMap<String, serde_json::Value>>,
+}
+
+/// `config.json` can't be parsed. The configuration is read-only until it's repaired.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ConfigParseError {
+    pub path: String,
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ConfigBackup {
+    // 1 is the newest.
+    pub index: usize,
+    // in seconds since the epoch
+    pub modified_at: Option<u64>,
 }
 
 impl Default for PluginConfig {
@@ -38,13 +78,24 @@ impl AppConfig {
     }
 
     pub fn load() -> anyhow::Result<AppConfig> {
-        let path = AppConfig::get_configuration_file_path();
+        Self::load_at(&AppConfig::get_configuration_file_path())
+    }
+
+    pub(crate) fn load_at(path: &Path) -> anyhow::Result<AppConfig> {
         log::info!("Loading configuration from {:?}", path);
+        if let Err(err) = check_security(path) {
+            log::error!("{:?}", err);
+            return Ok(AppConfig::default());
+        }
+        Self::load_from(path)
+    }
 
-        match File::open(path.clone()) {
-            Ok(file) => match serde_json::from_reader(file) {
+    fn load_from(path: &Path) -> anyhow::Result<AppConfig> {
+        match fs::read_to_string(path) {
+            Ok(json) => match parse_config(&json) {
                 Ok(config) => Ok(config),
                 Err(err) => {
+                    // The broken file is kept as is. `save` refuses to overwrite it.
                     log::error!(
                         "Cannot deserialize configuration file({:?}): {:?}",
                         path,
@@ -61,26 +112,453 @@ impl AppConfig {
         }
     }
 
+    pub fn event_deadline(&self) -> Duration {
+        Duration::from_millis(self.event_deadline_ms.unwrap_or(DEFAULT_EVENT_DEADLINE_MS))
+    }
+
     pub fn save(&self) -> anyhow::Result<()> {
-        let path = AppConfig::get_configuration_file_path();
-        fs::create_dir_all(path.parent().unwrap())?;
-        let json = serde_json::to_string(self)?;
-
-        let mut f = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(path)?;
-        f.write_all(json.as_bytes())?;
-        Ok(())
+        self.save_at(&AppConfig::get_configuration_file_path())
+    }
+
+    pub(crate) fn save_at(&self, path: &Path) -> anyhow::Result<()> {
+        // Don't overwrite the refused configuration with the default one.
+        check_security(path)?;
+        self.save_to(path)
+    }
+
+    fn save_to(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(err) = parse_error_at(path) {
+            return Err(anyhow!(
+                "{} is broken at line {}, column {}: {}. Repair it or restore a backup first.",
+                err.path,
+                err.line,
+                err.column,
+                err.message
+            ));
+        }
+        // Pretty printed, so the parse error points the line.
+        let json = serde_json::to_string_pretty(self)?;
+        write_atomically(path, json.as_bytes())
+    }
+
+    /// The parse error of `config.json`, if it's broken.
+    pub fn parse_error() -> Option<ConfigParseError> {
+        parse_error_at(&AppConfig::get_configuration_file_path())
+    }
+
+    pub fn list_backups() -> Vec<ConfigBackup> {
+        list_backups_at(&AppConfig::get_configuration_file_path())
+    }
+
+    /// Replace `config.json` with the backup. The current one becomes the newest backup.
+    pub fn restore_backup(index: usize) -> anyhow::Result<()> {
+        restore_backup_at(&AppConfig::get_configuration_file_path(), index)
+    }
+
+    /// Carry over the settings of the plugin, which was renamed or renamed its config keys.
+    /// The previous ids in `installed_ids` are other plugins, and their settings are left alone.
+    /// Returns true if the configuration is changed.
+    pub fn migrate_plugin(
+        &mut self,
+        plugin_id: &str,
+        previous_ids: &[String],
+        renamed_keys: &HashMap<String, String>,
+        installed_ids: &HashSet<String>,
+    ) -> bool {
+        let plugins = self.plugins.get_or_insert(HashMap::new());
+        let mut changed = false;
+        for previous_id in previous_ids {
+            if previous_id == plugin_id || installed_ids.contains(previous_id) {
+                continue;
+            }
+            if !plugins.contains_key(previous_id) {
+                continue;
+            }
+            if plugins.contains_key(plugin_id) {
+                // Keep the old settings. "Deleted plugins" in the settings removes them.
+                log::warn!(
+                    "Not moving the settings of {}, because {} has the settings",
+                    previous_id,
+                    plugin_id
+                );
+                continue;
+            }
+            if let Some(previous) = plugins.remove(previous_id) {
+                log::info!("Moving the settings of {} to {}", previous_id, plugin_id);
+                plugins.insert(plugin_id.to_string(), previous);
+                changed = true;
+            }
+        }
+
+        if let Some(config) = plugins
+            .get_mut(plugin_id)
+            .and_then(|plugin| plugin.config.as_mut())
+        {
+            for (old_key, new_key) in renamed_keys {
+                if config.contains_key(new_key) {
+                    continue;
+                }
+                if let Some(value) = config.remove(old_key) {
+                    log::info!("Renaming {}.{} to {}", plugin_id, old_key, new_key);
+                    config.insert(new_key.clone(), value);
+                    changed = true;
+                }
+            }
+        }
+        changed
+    }
+
+    /// The plugins with the settings, but not in `known_ids`.
+    pub fn unknown_plugins(&self, known_ids: &HashSet<String>) -> Vec<String> {
+        let mut unknown_ids: Vec<String> = self
+            .plugins
+            .iter()
+            .flat_map(|plugins| plugins.keys())
+            .filter(|id| !known_ids.contains(*id))
+            .cloned()
+            .collect();
+        unknown_ids.sort();
+        unknown_ids
+    }
+
+    /// Remove the settings of `plugin_ids`, which are still not in `known_ids`.
+    /// Returns the removed ids.
+    pub fn remove_unknown_plugins(
+        &mut self,
+        plugin_ids: &[String],
+        known_ids: &HashSet<String>,
+    ) -> Vec<String> {
+        let removed: Vec<String> = self
+            .unknown_plugins(known_ids)
+            .into_iter()
+            .filter(|id| plugin_ids.contains(id))
+            .collect();
+        if let Some(plugins) = self.plugins.as_mut() {
+            for id in &removed {
+                plugins.remove(id);
+            }
+        }
+        removed
+    }
+}
+
+// Other users must not be able to replace `config.json`, or the directory holding it.
+fn check_security(path: &Path) -> anyhow::Result<()> {
+    if let Some(config_dir) = path.parent() {
+        file_security::check_file(config_dir)?;
+    }
+    file_security::check_file(path)
+}
+
+// Parse `config.json`, migrating it from the older version.
+fn parse_config(json: &str) -> anyhow::Result<AppConfig> {
+    let value: serde_json::Value = serde_json::from_str(json)?;
+    if config_migration::version_of(&value) == CONFIG_VERSION {
+        // Parse the text again, so the error has the line number.
+        return Ok(serde_json::from_str(json)?);
+    }
+    Ok(serde_json::from_value(config_migration::migrate(value)?)?)
+}
+
+fn backup_path(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+fn parse_error_at(path: &Path) -> Option<ConfigParseError> {
+    let json = fs::read_to_string(path).ok()?;
+    match parse_config(&json) {
+        Ok(_) => None,
+        Err(err) => {
+            // The migration error doesn't have the position.
+            let (line, column) = err
+                .downcast_ref::<serde_json::Error>()
+                .map(|err| (err.line(), err.column()))
+                .unwrap_or((0, 0));
+            Some(ConfigParseError {
+                path: path.to_string_lossy().to_string(),
+                message: format!("{:#}", err),
+                line,
+                column,
+            })
+        }
     }
 }
 
+// Keep the current file as the newest backup, and drop the oldest one.
+fn rotate_backups(path: &Path) -> anyhow::Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    for index in (1..CONFIG_BACKUPS).rev() {
+        let from = backup_path(path, index);
+        if from.exists() {
+            fs::rename(&from, backup_path(path, index + 1))
+                .map_err(|err| anyhow!("Cannot rotate {:?}: {:?}", from, err))?;
+        }
+    }
+    fs::copy(path, backup_path(path, 1))
+        .map_err(|err| anyhow!("Cannot back up {:?}: {:?}", path, err))?;
+    Ok(())
+}
+
+// Write the temporary file and rename it, so `config.json` is never half-written.
+fn write_atomically(path: &Path, content: &[u8]) -> anyhow::Result<()> {
+    // The unchanged configuration would push out the older backups.
+    if fs::read(path).is_ok_and(|current| current == content) {
+        return Ok(());
+    }
+    fs::create_dir_all(path.parent().unwrap())?;
+    let tmp_path = path.with_extension("json.tmp");
+    let mut f = File::create(&tmp_path)
+        .map_err(|err| anyhow!("Cannot create {:?}: {:?}", tmp_path, err))?;
+    f.write_all(content)?;
+    f.sync_all()?;
+
+    rotate_backups(path)?;
+    fs::rename(&tmp_path, path)
+        .map_err(|err| anyhow!("Cannot rename {:?}: {:?}", tmp_path, err))?;
+    Ok(())
+}
+
+fn list_backups_at(path: &Path) -> Vec<ConfigBackup> {
+    (1..=CONFIG_BACKUPS)
+        .filter_map(|index| {
+            let metadata = fs::metadata(backup_path(path, index)).ok()?;
+            Some(ConfigBackup {
+                index,
+                modified_at: metadata
+                    .modified()
+                    .ok()
+                    .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                    .map(|modified| modified.as_secs()),
+            })
+        })
+        .collect()
+}
+
+fn restore_backup_at(path: &Path, index: usize) -> anyhow::Result<()> {
+    if index == 0 || index > CONFIG_BACKUPS {
+        return Err(anyhow!("Unknown backup: {}", index));
+    }
+    let backup = backup_path(path, index);
+    file_security::check_file(&backup)?;
+    let json = fs::read_to_string(&backup)
+        .map_err(|err| anyhow!("Cannot read {:?}: {:?}", backup, err))?;
+    parse_config(&json).map_err(|err| anyhow!("{:?} is broken: {:#}", backup, err))?;
+    log::info!("Restoring configuration from {:?}", backup);
+    write_atomically(path, json.as_bytes())
+}
+
 impl Default for AppConfig {
     fn default() -> Self {
         Self {
+            version: CONFIG_VERSION,
             plugins: Some(HashMap::new()),
             log_level: "info".to_string(),
+            event_deadline_ms: None,
+            panic_chord: None,
+            pause_hotkey: None,
+            remember_pause_state: None,
+            paused: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    fn config_with_log_level(log_level: &str) -> AppConfig {
+        AppConfig {
+            log_level: log_level.to_string(),
+            ..AppConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_load_migrates_old_config() -> anyhow::Result<()> {
+        let temp_dir = TempDir::with_prefix("config")?;
+        let path = temp_dir.path().join("config.json");
+        fs::write(
+            &path,
+            r#"{"log_level": "debug", "plugins": {"hello": {"enabled": false, "config": null}}}"#,
+        )?;
+
+        let config = AppConfig::load_from(&path)?;
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert_eq!(config.log_level, "debug");
+        let hello = &config.plugins.as_ref().unwrap()["hello"];
+        assert!(!hello.enabled);
+        assert_eq!(hello.config, Some(HashMap::new()));
+
+        // the newer config is read-only.
+        fs::write(
+            &path,
+            format!(
+                r#"{{"log_level": "info", "version": {}}}"#,
+                CONFIG_VERSION + 1
+            ),
+        )?;
+        assert!(parse_error_at(&path).is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate_plugin() {
+        let mut config = AppConfig::default();
+        config.plugins.as_mut().unwrap().insert(
+            "old-id".to_string(),
+            PluginConfig {
+                enabled: false,
+                config: Some(HashMap::from([("key".to_string(), json!("C-t"))])),
+            },
+        );
+        let renamed_keys = HashMap::from([("key".to_string(), "hotkey".to_string())]);
+
+        let installed_ids = HashSet::from(["new-id".to_string()]);
+
+        assert!(config.migrate_plugin(
+            "new-id",
+            &["old-id".to_string()],
+            &renamed_keys,
+            &installed_ids
+        ));
+        let plugins = config.plugins.as_ref().unwrap();
+        assert!(!plugins.contains_key("old-id"));
+        assert!(!plugins["new-id"].enabled);
+        assert_eq!(
+            plugins["new-id"].config,
+            Some(HashMap::from([("hotkey".to_string(), json!("C-t"))]))
+        );
+
+        // already migrated
+        assert!(!config.migrate_plugin(
+            "new-id",
+            &["old-id".to_string()],
+            &renamed_keys,
+            &installed_ids
+        ));
+    }
+
+    #[test]
+    fn test_migrate_plugin_keeps_settings() {
+        let mut config = AppConfig::default();
+        for id in ["old-id", "new-id", "other"] {
+            config
+                .plugins
+                .as_mut()
+                .unwrap()
+                .insert(id.to_string(), PluginConfig::default());
+        }
+        let previous_ids = vec!["old-id".to_string(), "other".to_string()];
+        let installed_ids = HashSet::from(["new-id".to_string(), "other".to_string()]);
+
+        // "new-id" has its own settings, and "other" is still installed.
+        assert!(!config.migrate_plugin("new-id", &previous_ids, &HashMap::new(), &installed_ids));
+        assert_eq!(config.plugins.as_ref().unwrap().len(), 3);
+
+        config.plugins.as_mut().unwrap().remove("new-id");
+        assert!(config.migrate_plugin("new-id", &previous_ids, &HashMap::new(), &installed_ids));
+        let plugins = config.plugins.as_ref().unwrap();
+        assert!(plugins.contains_key("new-id"));
+        assert!(plugins.contains_key("other"));
+        assert!(!plugins.contains_key("old-id"));
+    }
+
+    #[test]
+    fn test_remove_unknown_plugins() {
+        let mut config = AppConfig::default();
+        for id in ["hello", "deleted", "bundled.dynamic-macro"] {
+            config
+                .plugins
+                .as_mut()
+                .unwrap()
+                .insert(id.to_string(), PluginConfig::default());
         }
+        let known_ids = HashSet::from(["hello".to_string(), "bundled.dynamic-macro".to_string()]);
+        assert_eq!(config.unknown_plugins(&known_ids), vec!["deleted"]);
+
+        // only the confirmed ids are removed. "hello" is installed.
+        let confirmed = vec!["hello".to_string(), "unconfirmed".to_string()];
+        assert!(config
+            .remove_unknown_plugins(&confirmed, &known_ids)
+            .is_empty());
+        let confirmed = vec!["hello".to_string(), "deleted".to_string()];
+        assert_eq!(
+            config.remove_unknown_plugins(&confirmed, &known_ids),
+            vec!["deleted"]
+        );
+        assert_eq!(config.plugins.as_ref().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_save_rotates_backups() -> anyhow::Result<()> {
+        let temp_dir = TempDir::with_prefix("config")?;
+        let path = temp_dir.path().join("config.json");
+
+        for i in 0..(CONFIG_BACKUPS + 2) {
+            config_with_log_level(&format!("level{}", i)).save_to(&path)?;
+        }
+        assert_eq!(
+            AppConfig::load_from(&path)?.log_level,
+            format!("level{}", CONFIG_BACKUPS + 1)
+        );
+        let backups = list_backups_at(&path);
+        assert_eq!(backups.len(), CONFIG_BACKUPS);
+        assert!(!backup_path(&path, CONFIG_BACKUPS + 1).exists());
+        assert!(!path.with_extension("json.tmp").exists());
+
+        restore_backup_at(&path, 1)?;
+        assert_eq!(
+            AppConfig::load_from(&path)?.log_level,
+            format!("level{}", CONFIG_BACKUPS)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_unchanged_save_keeps_backups() -> anyhow::Result<()> {
+        let temp_dir = TempDir::with_prefix("config")?;
+        let path = temp_dir.path().join("config.json");
+
+        config_with_log_level("info").save_to(&path)?;
+        config_with_log_level("debug").save_to(&path)?;
+        config_with_log_level("debug").save_to(&path)?;
+        assert_eq!(list_backups_at(&path).len(), 1);
+        assert_eq!(
+            AppConfig::load_from(&backup_path(&path, 1))?.log_level,
+            "info"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_broken_config_is_read_only() -> anyhow::Result<()> {
+        let temp_dir = TempDir::with_prefix("config")?;
+        let path = temp_dir.path().join("config.json");
+        // the second save backs up the first one.
+        config_with_log_level("info").save_to(&path)?;
+        config_with_log_level("debug").save_to(&path)?;
+        let broken = "{\n  \"log_level\": \"info\",\n  oops\n}";
+        fs::write(&path, broken)?;
+
+        let err = parse_error_at(&path).unwrap();
+        assert_eq!(err.line, 3);
+        // the broken file isn't overwritten by the default configuration.
+        let config = AppConfig::load_from(&path)?;
+        assert!(config.save_to(&path).is_err());
+        assert_eq!(fs::read_to_string(&path)?, broken);
+
+        // restoring the backup repairs it, and keeps the broken one as the backup.
+        restore_backup_at(&path, 1)?;
+        assert_eq!(parse_error_at(&path), None);
+        assert_eq!(AppConfig::load_from(&path)?.log_level, "info");
+        assert_eq!(fs::read_to_string(backup_path(&path, 1))?, broken);
+        assert!(restore_backup_at(&path, 1).is_err());
+        Ok(())
     }
 }
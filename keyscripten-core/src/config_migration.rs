@@ -0,0 +1,145 @@
+use anyhow::anyhow;
+use serde_json::{json, Value};
+
+/// The version of `config.json` written by this application.
+pub const CONFIG_VERSION: u64 = 2;
+
+// `MIGRATIONS[n]` converts the version `n` to `n + 1`.
+// Add a new function here, and bump `CONFIG_VERSION` when the format of `config.json` changes.
+const MIGRATIONS: [fn(&mut Value) -> anyhow::Result<()>; CONFIG_VERSION as usize] =
+    [migrate_v0_to_v1, migrate_v1_to_v2];
+
+/// The version of the configuration. The files written before the versioning are version 0.
+pub(crate) fn version_of(config: &Value) -> u64 {
+    config.get("version").and_then(Value::as_u64).unwrap_or(0)
+}
+
+/// Convert the configuration to `CONFIG_VERSION`, one version at a time.
+pub(crate) fn migrate(mut config: Value) -> anyhow::Result<Value> {
+    let version = version_of(&config);
+    if version > CONFIG_VERSION {
+        return Err(anyhow!(
+            "config.json is version {}, but this application supports up to {}",
+            version,
+            CONFIG_VERSION
+        ));
+    }
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        log::info!("Migrating config.json from version {}", from);
+        migration(&mut config)
+            .map_err(|err| anyhow!("Cannot migrate config.json from version {}: {}", from, err))?;
+        config["version"] = json!(from + 1);
+    }
+    Ok(config)
+}
+
+fn as_object(config: &mut Value) -> anyhow::Result<&mut serde_json::Map<String, Value>> {
+    config
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("config.json must be an object"))
+}
+
+// `plugins` and the `config` of each plugin were optional. They're always present since v1.
+fn migrate_v0_to_v1(config: &mut Value) -> anyhow::Result<()> {
+    let config = as_object(config)?;
+    let plugins = config.entry("plugins").or_insert_with(|| json!({}));
+    if plugins.is_null() {
+        *plugins = json!({});
+    }
+    for (id, plugin) in as_object(plugins)?.iter_mut() {
+        let plugin = plugin
+            .as_object_mut()
+            .ok_or_else(|| anyhow!("plugins.{} must be an object", id))?;
+        let plugin_config = plugin.entry("config").or_insert_with(|| json!({}));
+        if plugin_config.is_null() {
+            *plugin_config = json!({});
+        }
+    }
+    Ok(())
+}
+
+// The plugin settings were strings, and `""` meant the default. They're typed values since v2, and
+// the missing value means the default.
+fn migrate_v1_to_v2(config: &mut Value) -> anyhow::Result<()> {
+    let config = as_object(config)?;
+    let Some(plugins) = config.get_mut("plugins") else {
+        return Ok(());
+    };
+    for (id, plugin) in as_object(plugins)?.iter_mut() {
+        if let Some(plugin_config) = plugin.get_mut("config").and_then(Value::as_object_mut) {
+            plugin_config.retain(|key, value| {
+                let empty = value.as_str() == Some("");
+                if empty {
+                    log::info!("Using the default of {}.{}", id, key);
+                }
+                !empty
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrate_v0_to_v1() -> anyhow::Result<()> {
+        let mut config = json!({
+            "log_level": "info",
+            "plugins": {
+                "hello": {"enabled": true, "config": null},
+                "world": {"enabled": false, "config": {"hotkey": "C-t"}},
+            },
+        });
+        migrate_v0_to_v1(&mut config)?;
+        assert_eq!(config["plugins"]["hello"]["config"], json!({}));
+        assert_eq!(
+            config["plugins"]["world"]["config"],
+            json!({"hotkey": "C-t"})
+        );
+
+        let mut config = json!({"log_level": "info", "plugins": null});
+        migrate_v0_to_v1(&mut config)?;
+        assert_eq!(config["plugins"], json!({}));
+
+        let mut config = json!({"log_level": "info"});
+        migrate_v0_to_v1(&mut config)?;
+        assert_eq!(config["plugins"], json!({}));
+
+        assert!(migrate_v0_to_v1(&mut json!([])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate_v1_to_v2() -> anyhow::Result<()> {
+        let mut config = json!({
+            "log_level": "info",
+            "plugins": {
+                "hello": {"enabled": true, "config": {"size": "", "hotkey": "C-t"}},
+                "world": {"enabled": false, "config": {}},
+            },
+        });
+        migrate_v1_to_v2(&mut config)?;
+        assert_eq!(
+            config["plugins"]["hello"]["config"],
+            json!({"hotkey": "C-t"})
+        );
+        assert_eq!(config["plugins"]["world"]["config"], json!({}));
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate() -> anyhow::Result<()> {
+        let config = migrate(json!({"log_level": "info"}))?;
+        assert_eq!(version_of(&config), CONFIG_VERSION);
+
+        // the current version is left as is.
+        let current = json!({"log_level": "info", "version": CONFIG_VERSION});
+        assert_eq!(migrate(current.clone())?, current);
+
+        // written by the newer application
+        assert!(migrate(json!({"log_level": "info", "version": CONFIG_VERSION + 1})).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,328 @@
+use crate::hotkey::HotKey;
+use crate::keycode;
+use anyhow::anyhow;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+/// A configuration option of the plugin, the element of `config_schema` in `registerPlugin`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ConfigItem {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(flatten)]
+    pub kind: ConfigKind,
+}
+
+/// The type of the option, and its constraints.
+///
+/// The older plugins wrote the numbers as strings, e.g. `"default": "64"`. They're still accepted.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ConfigKind {
+    String {
+        #[serde(default)]
+        default: String,
+    },
+    // Multiline text.
+    Text {
+        #[serde(default)]
+        default: String,
+    },
+    Boolean {
+        #[serde(default, deserialize_with = "lenient_bool")]
+        default: bool,
+    },
+    Enum {
+        choices: Vec<String>,
+        default: String,
+    },
+    Integer {
+        #[serde(default, deserialize_with = "lenient_i64")]
+        default: i64,
+        min: Option<i64>,
+        max: Option<i64>,
+    },
+    Float {
+        #[serde(default, deserialize_with = "lenient_f64")]
+        default: f64,
+        min: Option<f64>,
+        max: Option<f64>,
+    },
+    // The key name, e.g. `escape`. The plugin receives the keycode.
+    Key {
+        default: String,
+    },
+    // e.g. `C-t`
+    Hotkey {
+        default: String,
+    },
+    HotkeyList {
+        #[serde(default)]
+        default: Vec<String>,
+    },
+}
+
+fn lenient_bool<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    as_bool(&Value::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+}
+
+fn lenient_i64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<i64, D::Error> {
+    as_i64(&Value::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+}
+
+fn lenient_f64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    as_f64(&Value::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+}
+
+fn as_bool(value: &Value) -> anyhow::Result<bool> {
+    match value {
+        Value::Bool(b) => Ok(*b),
+        Value::String(s) if s == "true" => Ok(true),
+        Value::String(s) if s == "false" => Ok(false),
+        _ => Err(anyhow!("{} is not a boolean", value)),
+    }
+}
+
+fn as_i64(value: &Value) -> anyhow::Result<i64> {
+    match value {
+        Value::Number(n) => n.as_i64(),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+    .ok_or_else(|| anyhow!("{} is not an integer", value))
+}
+
+fn as_f64(value: &Value) -> anyhow::Result<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+    .filter(|f: &f64| f.is_finite())
+    .ok_or_else(|| anyhow!("{} is not a number", value))
+}
+
+fn as_str(value: &Value) -> anyhow::Result<&str> {
+    value
+        .as_str()
+        .ok_or_else(|| anyhow!("{} is not a string", value))
+}
+
+fn check_range<T: PartialOrd + std::fmt::Display>(
+    n: T,
+    min: Option<T>,
+    max: Option<T>,
+) -> anyhow::Result<T> {
+    if let Some(min) = min {
+        if n < min {
+            return Err(anyhow!("{} is less than {}", n, min));
+        }
+    }
+    if let Some(max) = max {
+        if n > max {
+            return Err(anyhow!("{} is greater than {}", n, max));
+        }
+    }
+    Ok(n)
+}
+
+fn check_hotkey(s: &str) -> anyhow::Result<Value> {
+    HotKey::from_str(s)?;
+    Ok(json!(s))
+}
+
+impl ConfigItem {
+    pub fn default_value(&self) -> Value {
+        match &self.kind {
+            ConfigKind::String { default }
+            | ConfigKind::Text { default }
+            | ConfigKind::Enum { default, .. }
+            | ConfigKind::Key { default }
+            | ConfigKind::Hotkey { default } => json!(default),
+            ConfigKind::Boolean { default } => json!(default),
+            ConfigKind::Integer { default, .. } => json!(default),
+            ConfigKind::Float { default, .. } => json!(default),
+            ConfigKind::HotkeyList { default } => json!(default),
+        }
+    }
+
+    /// Check the schema itself, e.g. the default must be one of the choices.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.validate_value(&self.default_value())
+            .map(|_| ())
+            .map_err(|err| anyhow!("Invalid default of {}: {}", self.name, err))
+    }
+
+    /// Check the value set by the user. Returns the value in the canonical type.
+    pub fn validate_value(&self, value: &Value) -> anyhow::Result<Value> {
+        match &self.kind {
+            ConfigKind::String { .. } => {
+                let s = as_str(value)?;
+                if s.contains('\n') {
+                    return Err(anyhow!("must be a single line"));
+                }
+                Ok(json!(s))
+            }
+            ConfigKind::Text { .. } => Ok(json!(as_str(value)?)),
+            ConfigKind::Boolean { .. } => Ok(json!(as_bool(value)?)),
+            ConfigKind::Enum { choices, .. } => {
+                let s = as_str(value)?;
+                if !choices.iter().any(|choice| choice == s) {
+                    return Err(anyhow!("{:?} is not one of {:?}", s, choices));
+                }
+                Ok(json!(s))
+            }
+            ConfigKind::Integer { min, max, .. } => {
+                Ok(json!(check_range(as_i64(value)?, *min, *max)?))
+            }
+            ConfigKind::Float { min, max, .. } => {
+                Ok(json!(check_range(as_f64(value)?, *min, *max)?))
+            }
+            ConfigKind::Key { .. } => {
+                let s = as_str(value)?;
+                keycode::get_keycode(s).ok_or_else(|| anyhow!("Unknown key: {:?}", s))?;
+                Ok(json!(s))
+            }
+            ConfigKind::Hotkey { .. } => check_hotkey(as_str(value)?),
+            ConfigKind::HotkeyList { .. } => match value {
+                Value::Array(hotkeys) => hotkeys
+                    .iter()
+                    .map(|hotkey| check_hotkey(as_str(hotkey)?))
+                    .collect::<anyhow::Result<Vec<_>>>()
+                    .map(Value::Array),
+                // A single hotkey is a list of one.
+                Value::String(s) => Ok(json!([check_hotkey(s)?])),
+                _ => Err(anyhow!("{} is not a list of hotkeys", value)),
+            },
+        }
+    }
+}
+
+/// Parse and check `config_schema` of the plugin.
+pub fn parse_config_schema(json: &str) -> anyhow::Result<Vec<ConfigItem>> {
+    let items: Vec<ConfigItem> =
+        serde_json::from_str(json).map_err(|err| anyhow!("Invalid config_schema: {}", err))?;
+    for item in &items {
+        item.validate()?;
+    }
+    Ok(items)
+}
+
+/// Check the values of the plugin's configuration. The values without the schema are kept as is.
+pub fn validate_config(
+    schema: &[ConfigItem],
+    config: &HashMap<String, Value>,
+) -> anyhow::Result<HashMap<String, Value>> {
+    let mut validated = config.clone();
+    let mut errors = Vec::new();
+    for item in schema {
+        if let Some(value) = config.get(&item.name) {
+            match item.validate_value(value) {
+                Ok(value) => {
+                    validated.insert(item.name.clone(), value);
+                }
+                Err(err) => errors.push(format!("{}: {}", item.name, err)),
+            }
+        }
+    }
+    if errors.is_empty() {
+        Ok(validated)
+    } else {
+        Err(anyhow!("{}", errors.join(", ")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(json: Value) -> ConfigItem {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn test_parse_config_schema() -> anyhow::Result<()> {
+        let schema = parse_config_schema(
+            r#"[
+                {"name": "hotkey", "type": "hotkey", "default": "C-t"},
+                {"name": "buffer_size", "type": "integer", "default": "64", "description": "legacy"},
+                {"name": "ratio", "type": "float", "default": 0.5, "min": 0, "max": 1},
+                {"name": "mode", "type": "enum", "choices": ["a", "b"], "default": "a"},
+                {"name": "leader", "type": "key", "default": "escape"},
+                {"name": "keys", "type": "hotkey_list", "default": ["C-a", "C-e"]},
+                {"name": "enabled", "type": "boolean", "default": true},
+                {"name": "note", "type": "text"}
+            ]"#,
+        )?;
+        assert_eq!(schema.len(), 8);
+        assert_eq!(schema[1].default_value(), json!(64));
+        assert_eq!(schema[1].description, "legacy");
+
+        assert!(parse_config_schema(r#"[{"name": "x", "type": "unknown"}]"#).is_err());
+        assert!(parse_config_schema(
+            r#"[{"name": "x", "type": "enum", "choices": ["a"], "default": "b"}]"#
+        )
+        .is_err());
+        assert!(parse_config_schema(
+            r#"[{"name": "x", "type": "integer", "default": 10, "max": 5}]"#
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_value() {
+        let integer = item(json!({"name": "n", "type": "integer", "min": 1, "max": 10}));
+        assert_eq!(integer.validate_value(&json!("5")).unwrap(), json!(5));
+        assert!(integer.validate_value(&json!(0)).is_err());
+        assert!(integer.validate_value(&json!("")).is_err());
+        assert!(integer.validate_value(&json!(1.5)).is_err());
+
+        let float = item(json!({"name": "f", "type": "float", "min": 0.0}));
+        assert_eq!(float.validate_value(&json!("0.25")).unwrap(), json!(0.25));
+        assert!(float.validate_value(&json!(-1)).is_err());
+
+        let boolean = item(json!({"name": "b", "type": "boolean"}));
+        assert_eq!(
+            boolean.validate_value(&json!("false")).unwrap(),
+            json!(false)
+        );
+        assert!(boolean.validate_value(&json!(0)).is_err());
+
+        let string = item(json!({"name": "s", "type": "string"}));
+        assert!(string.validate_value(&json!("a\nb")).is_err());
+        let text = item(json!({"name": "t", "type": "text"}));
+        assert!(text.validate_value(&json!("a\nb")).is_ok());
+
+        let key = item(json!({"name": "k", "type": "key", "default": "a"}));
+        assert!(key.validate_value(&json!("Escape")).is_ok());
+        assert!(key.validate_value(&json!("C-a")).is_err());
+
+        let hotkeys = item(json!({"name": "h", "type": "hotkey_list"}));
+        assert_eq!(
+            hotkeys.validate_value(&json!("C-t")).unwrap(),
+            json!(["C-t"])
+        );
+        assert!(hotkeys.validate_value(&json!(["C-t", "C-"])).is_err());
+    }
+
+    #[test]
+    fn test_validate_config() {
+        let schema = vec![
+            item(json!({"name": "n", "type": "integer", "default": 1})),
+            item(json!({"name": "b", "type": "boolean"})),
+        ];
+        let config = HashMap::from([
+            ("n".to_string(), json!("3")),
+            ("unknown".to_string(), json!("kept")),
+        ]);
+        let validated = validate_config(&schema, &config).unwrap();
+        assert_eq!(validated["n"], json!(3));
+        assert_eq!(validated["unknown"], json!("kept"));
+
+        let config = HashMap::from([("b".to_string(), json!("yes"))]);
+        assert!(validate_config(&schema, &config).is_err());
+    }
+}
@@ -0,0 +1,85 @@
+use crate::event::Event;
+use std::collections::HashSet;
+
+/// Tracks the keyDown events consumed by the plugins.
+///
+/// If a plugin drops the keyDown, the application shouldn't receive the paired keyUp and the
+/// autorepeat keyDowns for that key.
+#[derive(Default, Debug)]
+pub struct ConsumedKeys {
+    keycodes: HashSet<i64>,
+}
+
+impl ConsumedKeys {
+    /// Returns true if the event belongs to a consumed keyDown, and must be dropped.
+    pub fn is_suppressed(&mut self, event: &Event) -> bool {
+        match event.event_type.as_str() {
+            "keyDown" => {
+                if event.autorepeat {
+                    self.keycodes.contains(&event.keycode)
+                } else {
+                    // New key press. The previous keyUp was lost.
+                    self.keycodes.remove(&event.keycode);
+                    false
+                }
+            }
+            "keyUp" => self.keycodes.remove(&event.keycode),
+            _ => false,
+        }
+    }
+
+    /// Record the decision for the event.
+    pub fn record(&mut self, event: &Event, passed: bool) {
+        if event.event_type == "keyDown" && !event.autorepeat && !passed {
+            self.keycodes.insert(event.keycode);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.keycodes.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(event_type: &str, keycode: i64, autorepeat: bool) -> Event {
+        Event {
+            event_type: event_type.to_string(),
+            keycode,
+            flags: 0,
+            autorepeat,
+            timestamp: 0,
+        }
+    }
+
+    #[test]
+    fn test_suppress_orphan_key_up() {
+        let mut consumed = ConsumedKeys::default();
+
+        let key_down = event("keyDown", 17, false);
+        assert!(!consumed.is_suppressed(&key_down));
+        consumed.record(&key_down, false);
+
+        assert!(consumed.is_suppressed(&event("keyDown", 17, true)));
+        // other keys are not affected.
+        assert!(!consumed.is_suppressed(&event("keyUp", 18, false)));
+        assert!(consumed.is_suppressed(&event("keyUp", 17, false)));
+
+        // the next keyUp is not an orphan.
+        assert!(!consumed.is_suppressed(&event("keyUp", 17, false)));
+    }
+
+    #[test]
+    fn test_passed_key_down() {
+        let mut consumed = ConsumedKeys::default();
+
+        let key_down = event("keyDown", 17, false);
+        assert!(!consumed.is_suppressed(&key_down));
+        consumed.record(&key_down, true);
+
+        assert!(!consumed.is_suppressed(&event("keyDown", 17, true)));
+        assert!(!consumed.is_suppressed(&event("keyUp", 17, false)));
+    }
+}
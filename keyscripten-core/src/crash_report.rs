@@ -0,0 +1,63 @@
+use crate::session::get_active_plugin;
+use crate::APP_NAME;
+use anyhow::anyhow;
+use chrono::Local;
+use std::backtrace::Backtrace;
+use std::fs;
+use std::panic::{self, PanicHookInfo};
+use std::path::PathBuf;
+use std::thread;
+
+fn crash_report_dir() -> anyhow::Result<PathBuf> {
+    let data_dir = dirs::data_dir().ok_or_else(|| anyhow!("Cannot get data dir"))?;
+    Ok(data_dir.join(APP_NAME).join("crash"))
+}
+
+fn build_crash_report(info: &PanicHookInfo, backtrace: &Backtrace) -> String {
+    let message = if let Some(s) = info.payload().downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = info.payload().downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "(unknown panic payload)".to_string()
+    };
+    let location = match info.location() {
+        Some(location) => format!("{}:{}", location.file(), location.line()),
+        None => "(unknown location)".to_string(),
+    };
+
+    format!(
+        "time: {}\nthread: {}\nmessage: {}\nlocation: {}\nactive plugin: {}\n\n{}\n",
+        Local::now().to_rfc3339(),
+        thread::current().name().unwrap_or("(unnamed)"),
+        message,
+        location,
+        get_active_plugin().unwrap_or_else(|| "(none)".to_string()),
+        backtrace
+    )
+}
+
+fn write_crash_report(report: &str) -> anyhow::Result<PathBuf> {
+    let dir = crash_report_dir()?;
+    fs::create_dir_all(&dir)?;
+    let path = dir.join(format!(
+        "crash-{}.log",
+        Local::now().format("%Y%m%d-%H%M%S%.3f")
+    ));
+    fs::write(&path, report)?;
+    Ok(path)
+}
+
+/// Write a crash report to the data dir on every panic, then run the default hook.
+pub fn install_panic_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let report = build_crash_report(info, &Backtrace::force_capture());
+        log::error!("Panic: {}", report);
+        match write_crash_report(&report) {
+            Ok(path) => log::error!("Crash report was written to {:?}", path),
+            Err(err) => log::error!("Cannot write crash report: {:?}", err),
+        }
+        default_hook(info);
+    }));
+}
@@ -1,7 +1,7 @@
 use apple_sys::CoreGraphics::{
-    CGEventField_kCGKeyboardEventKeycode, CGEventGetFlags, CGEventGetIntegerValueField, CGEventRef,
-    CGEventType, CGEventType_kCGEventFlagsChanged, CGEventType_kCGEventKeyDown,
-    CGEventType_kCGEventKeyUp,
+    CGEventField_kCGKeyboardEventAutorepeat, CGEventField_kCGKeyboardEventKeycode, CGEventGetFlags,
+    CGEventGetIntegerValueField, CGEventRef, CGEventType, CGEventType_kCGEventFlagsChanged,
+    CGEventType_kCGEventKeyDown, CGEventType_kCGEventKeyUp,
 };
 use serde::{Deserialize, Serialize};
 use std::time::SystemTime;
@@ -22,6 +22,7 @@ pub struct Event {
     pub event_type: String,
     pub keycode: i64,
     pub flags: u64,
+    pub autorepeat: bool,
     pub timestamp: u64,
 }
 
@@ -32,6 +33,9 @@ impl Event {
             let keycode =
                 CGEventGetIntegerValueField(cg_event_ref, CGEventField_kCGKeyboardEventKeycode);
             let flags = CGEventGetFlags(cg_event_ref);
+            let autorepeat =
+                CGEventGetIntegerValueField(cg_event_ref, CGEventField_kCGKeyboardEventAutorepeat)
+                    != 0;
 
             let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
                 Ok(n) => n.as_secs(),
@@ -43,6 +47,7 @@ impl Event {
                 event_type: event_type(cg_event_type).to_string(),
                 keycode,
                 flags,
+                autorepeat,
             }
         }
     }
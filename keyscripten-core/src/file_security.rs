@@ -0,0 +1,317 @@
+use anyhow::anyhow;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+use std::sync::RwLock;
+
+// KeyScripten sees every keystroke. The files run by it must not be writable by other users.
+const WRITABLE_BY_OTHERS: u32 = 0o022;
+// Only the owner can rename or remove the entries in the sticky directory, e.g. `/tmp`.
+const STICKY_DIR: u32 = libc::S_IFDIR as u32 | libc::S_ISVTX as u32;
+
+lazy_static! {
+    // key is the path.
+    static ref INSECURE_FILES: RwLock<BTreeMap<String, InsecureFile>> =
+        RwLock::new(BTreeMap::new());
+}
+
+/// The file refused to load, since other users can modify it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct InsecureFile {
+    pub path: String,
+    pub reason: String,
+    // The command to fix it by hand.
+    pub fix_command: String,
+    // false if the app can't fix it. e.g. the file is owned by another user.
+    pub fixable: bool,
+}
+
+fn current_uid() -> u32 {
+    unsafe { libc::geteuid() }
+}
+
+fn inspect(path: &Path, mode: u32, uid: u32, current_uid: u32) -> Option<InsecureFile> {
+    let path = path.to_string_lossy().to_string();
+    if uid != current_uid && uid != 0 {
+        return Some(InsecureFile {
+            reason: format!("owned by another user(uid={})", uid),
+            fix_command: format!("sudo chown {} '{}'", current_uid, path),
+            fixable: false,
+            path,
+        });
+    }
+    if uid == 0 && mode & STICKY_DIR == STICKY_DIR {
+        return None;
+    }
+    if mode & WRITABLE_BY_OTHERS != 0 {
+        return Some(InsecureFile {
+            reason: format!("writable by other users(mode={:o})", mode & 0o777),
+            fix_command: format!("chmod go-w '{}'", path),
+            fixable: true,
+            path,
+        });
+    }
+    None
+}
+
+fn inspect_path(path: &Path) -> Option<InsecureFile> {
+    let metadata = fs::metadata(path).ok()?;
+    inspect(path, metadata.mode(), metadata.uid(), current_uid())
+}
+
+// The symlinked directory in the tree is not followed. It may be a loop, or lead out of the tree.
+fn inspect_symlinked_dir(path: &Path) -> Option<InsecureFile> {
+    let metadata = fs::symlink_metadata(path).ok()?;
+    if !metadata.file_type().is_symlink() || !path.is_dir() {
+        return None;
+    }
+    let target = fs::canonicalize(path).ok()?;
+    let path = path.to_string_lossy().to_string();
+    Some(InsecureFile {
+        reason: "symlink to a directory, which is not followed".to_string(),
+        fix_command: format!(
+            "rm '{}' && cp -R '{}' '{}'",
+            path,
+            target.to_string_lossy(),
+            path
+        ),
+        fixable: false,
+        path,
+    })
+}
+
+fn record(path: &Path, insecure: Option<InsecureFile>) {
+    match INSECURE_FILES.write() {
+        Ok(mut files) => {
+            let key = path.to_string_lossy().to_string();
+            match insecure {
+                Some(insecure) => {
+                    files.insert(key, insecure);
+                }
+                None => {
+                    files.remove(&key);
+                }
+            }
+        }
+        Err(err) => log::error!("Cannot get lock for insecure files: {:?}", err),
+    }
+}
+
+/// Check the owner and the permission bits of the file or the directory.
+/// The missing file is fine, since nobody can run it.
+pub fn check_file(path: &Path) -> anyhow::Result<()> {
+    let insecure = inspect_path(path);
+    record(path, insecure.clone());
+    match insecure {
+        Some(insecure) => Err(anyhow!(
+            "Refused to load {}: {}. Run `{}` to fix it.",
+            insecure.path,
+            insecure.reason,
+            insecure.fix_command
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Check the directory and everything under it. The error lists all the insecure entries.
+pub fn check_tree(dir: &Path) -> anyhow::Result<()> {
+    let mut insecure_files = Vec::new();
+    collect_insecure(dir, &mut insecure_files)?;
+    if insecure_files.is_empty() {
+        return Ok(());
+    }
+    let details: Vec<String> = insecure_files
+        .iter()
+        .map(|insecure| format!("{}: {}", insecure.path, insecure.reason))
+        .collect();
+    let fix_commands: Vec<&str> = insecure_files
+        .iter()
+        .map(|insecure| insecure.fix_command.as_str())
+        .collect();
+    Err(anyhow!(
+        "Refused to load {}. Run `{}` to fix it.",
+        details.join(", "),
+        fix_commands.join("; ")
+    ))
+}
+
+fn collect_insecure(path: &Path, insecure_files: &mut Vec<InsecureFile>) -> anyhow::Result<()> {
+    let insecure = inspect_path(path);
+    record(path, insecure.clone());
+    insecure_files.extend(insecure);
+    if !path.is_dir() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        let symlinked_dir = inspect_symlinked_dir(&path);
+        if symlinked_dir.is_some() {
+            record(&path, symlinked_dir.clone());
+            insecure_files.extend(symlinked_dir);
+            continue;
+        }
+        collect_insecure(&path, insecure_files)?;
+    }
+    Ok(())
+}
+
+/// The refused files which are still insecure.
+pub fn get_insecure_files() -> Vec<InsecureFile> {
+    match INSECURE_FILES.write() {
+        Ok(mut files) => {
+            // the files may be fixed or removed by hand.
+            files.retain(|path, _| {
+                let path = Path::new(path);
+                inspect_path(path).is_some() || inspect_symlinked_dir(path).is_some()
+            });
+            files.values().cloned().collect()
+        }
+        Err(err) => {
+            log::error!("Cannot get lock for insecure files: {:?}", err);
+            vec![]
+        }
+    }
+}
+
+/// Remove the write permission for the group and others. Only the refused files can be fixed.
+pub fn fix_permissions(path: &str) -> anyhow::Result<()> {
+    let insecure = get_insecure_files()
+        .into_iter()
+        .find(|insecure| insecure.path == path)
+        .ok_or_else(|| anyhow!("{} wasn't refused", path))?;
+    if !insecure.fixable {
+        return Err(anyhow!(
+            "Cannot fix {}: {}. Run `{}`.",
+            path,
+            insecure.reason,
+            insecure.fix_command
+        ));
+    }
+
+    let path = Path::new(path);
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_mode(permissions.mode() & !WRITABLE_BY_OTHERS);
+    fs::set_permissions(path, permissions)
+        .map_err(|err| anyhow!("Cannot change permissions of {:?}: {:?}", path, err))?;
+    log::info!("Fixed permissions of {:?}", path);
+    check_file(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_inspect() {
+        let path = Path::new("/tmp/plugin.js");
+        assert_eq!(inspect(path, 0o644, 501, 501), None);
+        // root owned files are fine.
+        assert_eq!(inspect(path, 0o644, 0, 501), None);
+
+        let insecure = inspect(path, 0o666, 501, 501).unwrap();
+        assert!(insecure.fixable);
+        assert_eq!(insecure.fix_command, "chmod go-w '/tmp/plugin.js'");
+        assert!(inspect(path, 0o664, 501, 501).is_some());
+
+        let insecure = inspect(path, 0o644, 502, 501).unwrap();
+        assert!(!insecure.fixable);
+
+        // like /tmp
+        assert_eq!(inspect(Path::new("/tmp"), 0o41777, 0, 501), None);
+        assert!(inspect(Path::new("/tmp/shared"), 0o41777, 502, 501).is_some());
+        assert!(inspect(Path::new("/tmp/shared"), 0o40777, 0, 501).is_some());
+    }
+
+    #[test]
+    fn test_fix_permissions() -> anyhow::Result<()> {
+        let temp_dir = TempDir::with_prefix("security")?;
+        let path = temp_dir.path().join("plugin.js");
+        fs::write(&path, "code")?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o666))?;
+
+        assert!(check_file(&path).is_err());
+        let path_str = path.to_string_lossy().to_string();
+        assert!(get_insecure_files()
+            .iter()
+            .any(|insecure| insecure.path == path_str));
+
+        fix_permissions(&path_str)?;
+        assert_eq!(fs::metadata(&path)?.mode() & 0o777, 0o644);
+        assert!(check_file(&path).is_ok());
+        assert!(!get_insecure_files()
+            .iter()
+            .any(|insecure| insecure.path == path_str));
+
+        // the files not refused can't be changed.
+        assert!(fix_permissions("/etc/hosts").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_tree() -> anyhow::Result<()> {
+        let temp_dir = TempDir::with_prefix("security")?;
+        let package_dir = temp_dir.path().join("package");
+        fs::create_dir_all(package_dir.join("lib"))?;
+        fs::write(package_dir.join("index.js"), "code")?;
+        fs::write(package_dir.join("lib").join("util.js"), "code")?;
+        for (path, mode) in [
+            (package_dir.clone(), 0o755),
+            (package_dir.join("lib"), 0o755),
+            (package_dir.join("index.js"), 0o644),
+            (package_dir.join("lib").join("util.js"), 0o644),
+        ] {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        }
+        assert!(check_tree(&package_dir).is_ok());
+
+        fs::set_permissions(
+            package_dir.join("lib").join("util.js"),
+            fs::Permissions::from_mode(0o646),
+        )?;
+        fs::set_permissions(
+            package_dir.join("index.js"),
+            fs::Permissions::from_mode(0o666),
+        )?;
+        let err = check_tree(&package_dir).unwrap_err().to_string();
+        assert!(err.contains("index.js"));
+        assert!(err.contains("util.js"));
+
+        // missing files can't be run.
+        assert!(check_tree(&temp_dir.path().join("missing")).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_tree_symlinked_dir() -> anyhow::Result<()> {
+        let temp_dir = TempDir::with_prefix("security")?;
+        let package_dir = temp_dir.path().join("package");
+        fs::create_dir(&package_dir)?;
+        fs::set_permissions(&package_dir, fs::Permissions::from_mode(0o755))?;
+        fs::write(package_dir.join("index.js"), "code")?;
+        fs::set_permissions(
+            package_dir.join("index.js"),
+            fs::Permissions::from_mode(0o644),
+        )?;
+        // the loop doesn't overflow the stack.
+        std::os::unix::fs::symlink(&package_dir, package_dir.join("loop"))?;
+
+        let err = check_tree(&package_dir).unwrap_err().to_string();
+        assert!(err.contains("loop: symlink to a directory"));
+        assert!(!err.contains("index.js"));
+        let loop_path = package_dir.join("loop").to_string_lossy().to_string();
+        assert!(get_insecure_files()
+            .iter()
+            .any(|insecure| insecure.path == loop_path && !insecure.fixable));
+
+        fs::remove_file(package_dir.join("loop"))?;
+        assert!(check_tree(&package_dir).is_ok());
+        assert!(!get_insecure_files()
+            .iter()
+            .any(|insecure| insecure.path == loop_path));
+        Ok(())
+    }
+}
@@ -1,25 +1,44 @@
 #![allow(improper_ctypes_definitions)]
 
-use crate::js::JS;
+use crate::consumed_keys::ConsumedKeys;
+use crate::event::Event;
+use crate::kill_switch::KillSwitch;
+use crate::modifier_state::observe_physical_flags;
+use crate::output;
+use crate::passthrough::{engage_kill_switch, is_passthrough, resume, set_paused, PauseHotKey};
+use crate::pressed_keys::{clear_pressed_keys, observe_key_event};
 use crate::send::USER_DATA_FROM_THIS_APP;
+use crate::worker::Worker;
 use anyhow::anyhow;
 use apple_sys::CoreGraphics::{
-    kCFAllocatorDefault, kCFRunLoopCommonModes, CFMachPortCreateRunLoopSource, CFRunLoopAddSource,
-    CFRunLoopGetCurrent, CFRunLoopRun,
+    kCFAllocatorDefault, kCFRunLoopCommonModes, CFMachPortCreateRunLoopSource, CFMachPortRef,
+    CFRunLoopAddSource, CFRunLoopGetCurrent, CFRunLoopRun,
 };
 use apple_sys::CoreGraphics::{
     CGEventField_kCGEventSourceUserData, CGEventGetIntegerValueField, CGEventMask, CGEventRef,
     CGEventSetType, CGEventTapCreate, CGEventTapEnable, CGEventTapLocation_kCGHIDEventTap,
     CGEventTapOptions_kCGEventTapOptionDefault, CGEventTapPlacement_kCGHeadInsertEventTap,
     CGEventTapProxy, CGEventType, CGEventType_kCGEventFlagsChanged, CGEventType_kCGEventKeyDown,
-    CGEventType_kCGEventKeyUp, CGEventType_kCGEventNull,
+    CGEventType_kCGEventKeyUp, CGEventType_kCGEventNull, CGEventType_kCGEventTapDisabledByTimeout,
+    CGEventType_kCGEventTapDisabledByUserInput,
 };
 use cocoa::base::nil;
 use cocoa::foundation::NSAutoreleasePool;
+use std::panic::{self, AssertUnwindSafe};
+use std::time::Instant;
 
 #[link(name = "Cocoa", kind = "framework")]
 extern "C" {}
 
+// State of the event tap callback.
+struct Handler {
+    worker: Worker,
+    tap: CFMachPortRef,
+    consumed_keys: ConsumedKeys,
+    kill_switch: KillSwitch,
+    pause_hotkey: Option<PauseHotKey>,
+}
+
 // This event is sent from this application itself.
 unsafe fn is_sent_from_this_app(cg_event: CGEventRef) -> bool {
     let user_data = CGEventGetIntegerValueField(cg_event, CGEventField_kCGEventSourceUserData);
@@ -34,28 +53,99 @@ unsafe extern "C" fn raw_callback(
 ) -> CGEventRef {
     log::debug!("Called raw_callback");
 
+    let handler = &mut *(user_info as *mut Handler);
+
+    // Unwinding across the FFI boundary is undefined behavior. Pass the event through instead.
+    match panic::catch_unwind(AssertUnwindSafe(|| {
+        handle_event(handler, event_type, cg_event)
+    })) {
+        Ok(cg_event) => cg_event,
+        Err(_) => {
+            log::error!("Panic in the event tap callback. The event is passed through.");
+            cg_event
+        }
+    }
+}
+
+unsafe fn handle_event(
+    handler: &mut Handler,
+    event_type: CGEventType,
+    cg_event: CGEventRef,
+) -> CGEventRef {
+    // The OS disables the tap when the callback is too slow.
+    if event_type == CGEventType_kCGEventTapDisabledByTimeout
+        || event_type == CGEventType_kCGEventTapDisabledByUserInput
+    {
+        log::warn!("Event tap was disabled({}). Restarting.", event_type);
+        clear_pressed_keys();
+        handler.consumed_keys.clear();
+        CGEventTapEnable(handler.tap, true);
+        return cg_event;
+    }
+
     if is_sent_from_this_app(cg_event) {
         return cg_event;
     }
 
-    let js = &mut *(user_info as *mut JS);
-    match js.send_event(event_type, cg_event) {
-        Ok(b) => {
-            if !b {
-                log::debug!("Don't send keyboard event to the destination.");
-                CGEventSetType(cg_event, CGEventType_kCGEventNull);
+    let event = Event::from_cf(event_type, cg_event);
+    observe_physical_flags(event.flags);
+    observe_key_event(&event);
+
+    // Checked before the plugins, so it works even if the plugins are broken.
+    if handler.kill_switch.observe(&event, Instant::now()) {
+        engage_kill_switch();
+    }
+    if let Some(pause_hotkey) = &handler.pause_hotkey {
+        if pause_hotkey.matches(&event) {
+            if is_passthrough() {
+                resume();
+            } else {
+                set_paused(true);
             }
+            // Its keyUp and autorepeat keyDowns are dropped too.
+            handler.consumed_keys.record(&event, false);
+            CGEventSetType(cg_event, CGEventType_kCGEventNull);
+            return cg_event;
         }
-        Err(err) => {
-            log::error!("Cannot call JS callback: {:?}", err);
+    }
+
+    // The keyUp paired with the consumed keyDown is dropped, even if the worker is late or the
+    // plugins are paused.
+    let suppressed = handler.consumed_keys.is_suppressed(&event);
+    if is_passthrough() {
+        if suppressed {
+            CGEventSetType(cg_event, CGEventType_kCGEventNull);
         }
+        return cg_event;
+    }
+
+    let (pass, pending) = handler.worker.send_event(&event, suppressed);
+    let pass = !suppressed && pass;
+    handler.consumed_keys.record(&event, pass);
+
+    // Post the events sent by the plugins, after the decision.
+    let reposted = output::flush_pending(pending, if pass { Some(cg_event) } else { None });
+    if !pass || reposted {
+        log::debug!("Don't send keyboard event to the destination.");
+        CGEventSetType(cg_event, CGEventType_kCGEventNull);
     }
 
     cg_event
 }
 
-pub fn grab_setup(js: JS) -> anyhow::Result<()> {
+pub fn grab_setup(
+    worker: Worker,
+    kill_switch: KillSwitch,
+    pause_hotkey: Option<PauseHotKey>,
+) -> anyhow::Result<()> {
     unsafe {
+        let handler = Box::into_raw(Box::new(Handler {
+            worker,
+            tap: std::ptr::null_mut(),
+            consumed_keys: ConsumedKeys::default(),
+            kill_switch,
+            pause_hotkey,
+        }));
         let _pool = NSAutoreleasePool::new(nil);
         log::debug!("Calling CGEventTapCreate");
         let tap = CGEventTapCreate(
@@ -66,11 +156,12 @@ pub fn grab_setup(js: JS) -> anyhow::Result<()> {
                 + (1 << CGEventType_kCGEventKeyUp as CGEventMask)
                 + (1 << CGEventType_kCGEventFlagsChanged as CGEventMask),
             Some(raw_callback),
-            Box::into_raw(Box::new(js)) as *mut _,
+            handler as *mut _,
         );
         if tap.is_null() {
             return Err(anyhow!("Cannot create CGEventTapCreate"));
         }
+        (*handler).tap = tap;
         let _loop = CFMachPortCreateRunLoopSource(kCFAllocatorDefault, tap, 0);
         if _loop.is_null() {
             return Err(anyhow!("Error in CFMachPortCreateRunLoopSource"));
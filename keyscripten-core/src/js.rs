@@ -4,37 +4,76 @@ use apple_sys::CoreGraphics::{
     CGEventFlags_kCGEventFlagMaskAlternate, CGEventFlags_kCGEventFlagMaskCommand,
     CGEventFlags_kCGEventFlagMaskControl, CGEventFlags_kCGEventFlagMaskHelp,
     CGEventFlags_kCGEventFlagMaskNonCoalesced, CGEventFlags_kCGEventFlagMaskNumericPad,
-    CGEventFlags_kCGEventFlagMaskSecondaryFn, CGEventFlags_kCGEventFlagMaskShift, CGEventRef,
-    CGEventType, CGEventType_kCGEventFlagsChanged, CGEventType_kCGEventKeyDown,
-    CGEventType_kCGEventKeyUp,
+    CGEventFlags_kCGEventFlagMaskSecondaryFn, CGEventFlags_kCGEventFlagMaskShift,
+    CGEventType_kCGEventFlagsChanged, CGEventType_kCGEventKeyDown, CGEventType_kCGEventKeyUp,
+};
+use boa_engine::builtins::promise::PromiseState;
+use boa_engine::realm::Realm;
+use boa_engine::{
+    js_string, Context, JsArgs, JsNativeError, JsObject, JsValue, Module, NativeFunction, Source,
 };
-use boa_engine::{js_string, Context, JsObject, JsValue, NativeFunction, Source};
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt::Debug;
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
 use std::sync::mpsc::{Receiver, TryRecvError};
 use std::sync::{Arc, RwLock};
 
 use boa_engine::native_function::NativeFunctionPointer;
 use boa_engine::object::builtins::JsFunction;
+use boa_engine::object::IntegrityLevel;
 use boa_engine::property::{Attribute, PropertyKey};
 use boa_engine::value::TryFromJs;
 
+use crate::app_config::AppConfig;
+use crate::config_schema::ConfigItem;
 use crate::event::Event;
-use crate::js_console::Console;
+use crate::js_console::{finish_capture, start_capture, Console, TimedLogMessage};
 use serde::{Deserialize, Serialize};
 
-use crate::js_builtin::JsBuiltin;
+use crate::js_builtin::{check_budget, take_aborted, JsBuiltin};
 use crate::js_hotkey::JsHotKey;
+use crate::js_job_queue::{PluginHostHooks, PluginJobQueue};
 use crate::js_keycode::build_keycode;
-use crate::js_operation::JsOperation;
-use crate::plugin::Plugins;
+use crate::js_module::PluginModuleLoader;
+use crate::js_operation::{report_js_operation_result, JsOperation, JsOperationResult};
+use crate::plugin::{resolve_in_package, PluginSnippet, Plugins};
+use crate::plugin_manifest::PluginManifest;
+
+// Functions defined in driver.js, exposed to the plugins.
+const DRIVER_API: [&str; 4] = ["registerPlugin", "setTimeout", "clearTimeout", "sleep"];
+
+const BUNDLED_DYNAMIC_MACRO: &str = "bundled:dynamic-macro.js";
+/// The ids of the plugins bundled with the app. They are always installed.
+pub const BUNDLED_PLUGIN_IDS: [&str; 1] = ["builtin.dynamicmacro"];
+
+// Runtime limits to abort the runaway plugins. Otherwise, the keyboard freezes.
+const LOOP_ITERATION_LIMIT: u64 = 1_000_000;
+const RECURSION_LIMIT: usize = 1024;
+
+static HOST_HOOKS: PluginHostHooks = PluginHostHooks;
+
+// Each script runs in its own realm. Plugins can't touch the driver's globals and
+// other plugin's globals.
+struct PluginRealm {
+    realm: Realm,
+    plugin_ids: Vec<String>,
+}
 
 pub struct JS {
     context: Context,
+    driver_realm: Realm,
+    // key is the filename of the script.
+    plugin_realms: HashMap<String, PluginRealm>,
     js_operation_rx: Option<Receiver<JsOperation>>,
     monitoring_queue: Option<Arc<RwLock<VecDeque<Event>>>>,
     plugins: Option<Plugins>,
     plugin_id2filename: HashMap<String, String>,
+    module_loader: Rc<PluginModuleLoader>,
+    job_queue: Rc<PluginJobQueue>,
+    // No plugins are loaded, until the app restarts.
+    safe_mode: bool,
 }
 
 impl JS {
@@ -43,25 +82,136 @@ impl JS {
         monitoring_queue: Option<Arc<RwLock<VecDeque<Event>>>>,
         plugins: Option<Plugins>,
     ) -> anyhow::Result<Self> {
-        let context = Context::default();
+        let module_loader = Rc::new(PluginModuleLoader::new(
+            plugins.as_ref().map(|plugins| plugins.lib_dir()),
+        ));
+        let job_queue = Rc::new(PluginJobQueue::default());
+        let mut context = Context::builder()
+            .module_loader(module_loader.clone())
+            .job_queue(job_queue.clone())
+            .host_hooks(&HOST_HOOKS)
+            .build()
+            .map_err(|err| anyhow!("Cannot build context: {:?}", err))?;
+        context
+            .runtime_limits_mut()
+            .set_loop_iteration_limit(LOOP_ITERATION_LIMIT);
+        context
+            .runtime_limits_mut()
+            .set_recursion_limit(RECURSION_LIMIT);
+        let driver_realm = context.realm().clone();
 
         let mut js = JS {
             context,
+            driver_realm,
+            plugin_realms: HashMap::new(),
             js_operation_rx,
             monitoring_queue,
             plugins,
             plugin_id2filename: HashMap::new(),
+            module_loader,
+            job_queue,
+            safe_mode: false,
         };
-        js.init_console()?;
-        js.init_hotkey()?;
-        js.init_keycode()?;
-        js.register_constants()?;
-        js.register_builtin_functions()?;
+        js.install_api()?;
+        js.register_driver_functions()?;
         js.load_driver()?;
         js.load_bundled()?;
         Ok(js)
     }
 
+    // Register the builtin objects and functions to the current realm.
+    fn install_api(&mut self) -> anyhow::Result<()> {
+        self.init_console()?;
+        self.init_hotkey()?;
+        self.init_keycode()?;
+        self.register_constants()?;
+        self.register_builtin_functions()?;
+        Ok(())
+    }
+
+    // Expose the driver's public API to the current realm.
+    // They are frozen, since they are shared with every plugin.
+    fn install_driver_api(&mut self) -> anyhow::Result<()> {
+        for name in DRIVER_API {
+            let value = self
+                .driver_realm
+                .global_object()
+                .get(js_string!(name), &mut self.context)
+                .map_err(|err| anyhow!("Cannot get `{}` from driver: {:?}", name, err))?;
+            if let Some(object) = value.as_object() {
+                object
+                    .set_integrity_level(IntegrityLevel::Frozen, &mut self.context)
+                    .map_err(|err| anyhow!("Cannot freeze `{}`: {:?}", name, err))?;
+            }
+            self.register_constant(js_string!(name), value)?;
+        }
+        Ok(())
+    }
+
+    // Evaluate the ES module of the plugin in the current realm. The imports are resolved by the
+    // module loader, for the plugin.
+    fn eval_module(&mut self, snippet: &PluginSnippet) -> anyhow::Result<()> {
+        let source = Source::from_bytes(snippet.src.as_bytes());
+        let module = match &snippet.path {
+            Some(path) => Module::parse(source.with_path(path), None, &mut self.context),
+            None => Module::parse(source, None, &mut self.context),
+        }
+        .map_err(|err| anyhow!("Cannot parse module: {:?}", err))?;
+        self.module_loader
+            .register_plugin(&module, &snippet.filename, snippet.package_dir.clone());
+
+        let promise = module.load_link_evaluate(&mut self.context);
+        self.context.run_jobs();
+        match promise.state() {
+            PromiseState::Rejected(err) => {
+                Err(anyhow!("Cannot evaluate module: {}", err.display()))
+            }
+            // The manifest is checked right after the evaluation, so the plugin must be registered
+            // by then.
+            PromiseState::Pending => Err(anyhow!(
+                "The module must not await on the top level. Call registerPlugin synchronously."
+            )),
+            PromiseState::Fulfilled(_) => Ok(()),
+        }
+    }
+
+    // `readPluginResource(path)` reads the file in the plugin package.
+    fn install_package_api(&mut self, package_dir: &Path) -> anyhow::Result<()> {
+        let package_dir = package_dir
+            .to_str()
+            .ok_or_else(|| anyhow!("Invalid package directory: {:?}", package_dir))?
+            .to_string();
+        let function = NativeFunction::from_copy_closure_with_captures(
+            |_this, args, package_dir, context| {
+                let path = args
+                    .get_or_undefined(0)
+                    .to_string(context)?
+                    .to_std_string_escaped();
+                resolve_in_package(Path::new(package_dir), &path)
+                    .and_then(|path| {
+                        fs::read_to_string(&path)
+                            .map_err(|err| anyhow!("Cannot read {:?}: {:?}", path, err))
+                    })
+                    .map(|content| JsValue::from(js_string!(content)))
+                    .map_err(|err| JsNativeError::error().with_message(err.to_string()).into())
+            },
+            package_dir,
+        );
+        self.context
+            .register_global_callable(js_string!("readPluginResource"), 1, function)
+            .map_err(|err| anyhow!("Cannot register readPluginResource: {:?}", err))
+    }
+
+    fn driver_function(&mut self, name: &str) -> anyhow::Result<JsFunction> {
+        let function = self
+            .driver_realm
+            .global_object()
+            .get(js_string!(name), &mut self.context)
+            .map_err(|err| anyhow!("Cannot get {}: {:?}", name, err))?;
+        JsFunction::try_from_js(&function, &mut self.context)
+            .map_err(|err| anyhow!("Cannot get {} as JsFunction: {:?}", name, err))
+    }
+
     pub fn get_filename_by_plugin_id(&self, plugin_id: &String) -> Option<String> {
         let result = self.plugin_id2filename.get(plugin_id).cloned();
         if result == None {
@@ -160,39 +310,121 @@ impl JS {
         Ok(())
     }
 
-    fn register_builtin_functions(&mut self) -> anyhow::Result<()> {
-        fn register(
-            context: &mut Context,
-            name: &str,
-            fn_ptr: NativeFunctionPointer,
-        ) -> anyhow::Result<()> {
-            if let Err(err) =
-                context.register_global_callable(js_string!(name), 1, NativeFunction::from_fn_ptr(fn_ptr))
-            {
-                return Err(anyhow!("Cannot register `{}` function: {:?}", name, err));
-            }
+    fn register_function(
+        &mut self,
+        name: &str,
+        fn_ptr: NativeFunctionPointer,
+    ) -> anyhow::Result<()> {
+        if let Err(err) = self.context.register_global_callable(
+            js_string!(name),
+            1,
+            NativeFunction::from_fn_ptr(fn_ptr),
+        ) {
+            return Err(anyhow!("Cannot register `{}` function: {:?}", name, err));
+        }
 
-            Ok(())
+        Ok(())
+    }
+
+    // The plugin-facing function checks the execution budget first.
+    fn register_plugin_function(
+        &mut self,
+        name: &str,
+        fn_ptr: NativeFunctionPointer,
+    ) -> anyhow::Result<()> {
+        if let Err(err) = self.context.register_global_callable(
+            js_string!(name),
+            1,
+            NativeFunction::from_copy_closure(move |this, args, context| {
+                check_budget()?;
+                fn_ptr(this, args, context)
+            }),
+        ) {
+            return Err(anyhow!("Cannot register `{}` function: {:?}", name, err));
         }
 
-        register(
-            &mut self.context,
+        Ok(())
+    }
+
+    // Public API for the plugins.
+    fn register_builtin_functions(&mut self) -> anyhow::Result<()> {
+        self.register_plugin_function(
             "sendFlagsChangedEvent",
             JsBuiltin::send_flags_changed_event,
         )?;
-        register(
-            &mut self.context,
-            "sendKeyboardEvent",
-            JsBuiltin::send_keyboard_event,
-        )?;
-        register(
-            &mut self.context,
-            "$$loadAppConfigJson",
-            JsBuiltin::load_app_config_json,
+        self.register_plugin_function("sendKeyboardEvent", JsBuiltin::send_keyboard_event)?;
+        self.register_plugin_function("sendKeys", JsBuiltin::send_keys)?;
+        self.register_plugin_function("getModifierState", JsBuiltin::get_modifier_state)?;
+        self.register_plugin_function("releaseAllModifiers", JsBuiltin::release_all_modifiers)?;
+        self.register_plugin_function("isPressed", JsBuiltin::is_pressed)?;
+        self.register_plugin_function("pressedKeys", JsBuiltin::pressed_keys)?;
+        Ok(())
+    }
+
+    // Functions only for driver.js. They are not exposed to the plugins.
+    fn register_driver_functions(&mut self) -> anyhow::Result<()> {
+        self.register_function("$$resyncModifiers", JsBuiltin::resync_modifiers)?;
+        self.register_function("$$reportPluginError", JsBuiltin::report_plugin_error)?;
+        self.register_function("$$resetPluginFault", JsBuiltin::reset_plugin_fault)?;
+        self.register_function("$$faultPlugin", JsBuiltin::fault_plugin)?;
+        self.register_function("$$loadAppConfigJson", JsBuiltin::load_app_config_json)?;
+        self.register_function("$$setActivePlugin", JsBuiltin::set_active_plugin)?;
+        self.register_function(
+            "$$normalizeConfigSchema",
+            JsBuiltin::normalize_config_schema,
         )?;
+        self.register_function("$$getKeycode", JsBuiltin::get_keycode)?;
         Ok(())
     }
 
+    /// Evaluate the code from the REPL, in the driver's scope or in the plugin's scope.
+    pub fn eval_in_repl(&mut self, code: &str, plugin_id: Option<&str>) -> EvalResult {
+        let realm = match plugin_id {
+            Some(plugin_id) => match self
+                .plugin_realms
+                .values()
+                .find(|plugin_realm| plugin_realm.plugin_ids.iter().any(|id| id == plugin_id))
+            {
+                Some(plugin_realm) => Some(plugin_realm.realm.clone()),
+                None => {
+                    return EvalResult {
+                        value: None,
+                        error: Some(format!("Unknown plugin: {}", plugin_id)),
+                        console: vec![],
+                    }
+                }
+            },
+            None => None,
+        };
+
+        start_capture();
+        let driver_realm = realm.map(|realm| self.context.enter_realm(realm));
+        let result = self
+            .context
+            .eval(Source::from_bytes(code))
+            .map(|value| value.display().to_string());
+        if let Some(driver_realm) = driver_realm {
+            self.context.enter_realm(driver_realm);
+        }
+        // The promise jobs queued by the code run under the budget, like the plugins' jobs.
+        // The code is evaluated as a script, so `await` on the top level is a syntax error.
+        self.run_jobs();
+        let console = finish_capture();
+
+        match result {
+            Ok(value) => EvalResult {
+                value: Some(value),
+                error: None,
+                console,
+            },
+            Err(err) => EvalResult {
+                value: None,
+                error: Some(err.to_string()),
+                console,
+            },
+        }
+    }
+
     pub fn eval(&mut self, src: String) -> anyhow::Result<JsValue> {
         return match self.context.eval(Source::from_bytes(&src)) {
             Ok(value) => Ok(value),
@@ -200,42 +432,55 @@ impl JS {
         };
     }
 
-    // Call this method when key/mouse event was received.
-    // This method calls JS handlers.
-    pub fn send_event(
-        &mut self,
-        cg_event_type: CGEventType,
-        cg_event_ref: CGEventRef,
-    ) -> anyhow::Result<bool> {
-        let invoke_event = self
-            .context
-            .global_object()
-            .get(js_string!("$$invokeEvent"), &mut self.context)
-            .map_err(|err| anyhow!("Cannot get $$invokeEvent: {:?}", err))?;
-        let invoke_event = JsFunction::try_from_js(&invoke_event, &mut self.context)
-            .map_err(|err| anyhow!("Cannot get $$invokeEvent as JsFunction: {:?}", err))?;
-
-        let mut needs_config_reload = false;
-        let operations = self.get_js_operations();
-        for op in operations {
-            match op {
-                JsOperation::ReloadConfig => {
-                    needs_config_reload = true;
+    // Call this method periodically, and before the event is handled.
+    // This method applies the queued operations, and reports the results.
+    pub fn process_js_operations(&mut self) {
+        for op in self.get_js_operations() {
+            let name = op.name();
+            let result = match op {
+                JsOperation::ReloadConfig => self.reload_config(),
+                JsOperation::ReloadPlugins => self.reload_plugins(),
+                JsOperation::UnloadPlugin { plugin_id, .. } => self.unload_plugin(plugin_id),
+                JsOperation::SetPluginEnabled { plugin_id, enabled } => {
+                    self.set_plugin_enabled(&plugin_id, enabled)
                 }
-                JsOperation::ReloadPlugins => {
-                    if let Err(err) = self.reload_plugins() {
-                        log::error!("cannot reload plugin: {:?}", err)
+                // The queries are answered by the reply channel, not reported.
+                JsOperation::GetConfigSchema { reply } => {
+                    if let Err(err) = reply.send(self.get_config_schema()) {
+                        log::error!("Cannot reply to {}: {:?}", name, err);
                     }
+                    continue;
                 }
-                JsOperation::UnloadPlugin { plugin_id, .. } => {
-                    if let Err(err) = self.unload_plugin(plugin_id.clone()) {
-                        log::error!("cannot unload plugin({}): {:?}", plugin_id, err)
+                JsOperation::GetFilenameByPluginId { plugin_id, reply } => {
+                    if let Err(err) = reply.send(self.get_filename_by_plugin_id(&plugin_id)) {
+                        log::error!("Cannot reply to {}: {:?}", name, err);
                     }
+                    continue;
                 }
+                JsOperation::Eval {
+                    code,
+                    plugin_id,
+                    reply,
+                } => {
+                    if let Err(err) = reply.send(self.eval_in_repl(&code, plugin_id.as_deref())) {
+                        log::error!("Cannot reply to {}: {:?}", name, err);
+                    }
+                    continue;
+                }
+            };
+            if let Err(err) = &result {
+                log::error!("Cannot apply {}: {:?}", name, err);
             }
+            report_js_operation_result(JsOperationResult::new(name, result));
         }
+    }
+
+    // Call this method when key/mouse event was received.
+    // This method calls JS handlers.
+    // `suppressed` is true if the event is the keyUp paired with the consumed keyDown.
+    pub fn send_event(&mut self, event: &Event, suppressed: bool) -> anyhow::Result<bool> {
+        let invoke_event = self.driver_function("$$invokeEvent")?;
 
-        let event = Event::from_cf(cg_event_type, cg_event_ref);
         if let Some(queue) = &self.monitoring_queue {
             match queue.write() {
                 Ok(mut queue) => {
@@ -250,19 +495,105 @@ impl JS {
             }
         }
 
-        let js_key_event = self.build_key_event(&event, cg_event_type)?;
-        let result = invoke_event
-            .call(
+        // The suppressed event is dropped anyway. Only the plugins opted in see it.
+        let js_key_event = self.build_key_event(event, suppressed)?;
+        let result = match invoke_event.call(
+            &JsValue::undefined(),
+            &[JsValue::from(js_key_event)],
+            &mut self.context,
+        ) {
+            Ok(result) => result,
+            Err(err) => {
+                // The runtime limit errors can't be caught by the plugin, nor driver.js.
+                self.fault_current_plugin(format!("{}", err));
+                return Err(anyhow!(
+                    "Cannot call $$invokeEvent as JsFunction: {:?}",
+                    err
+                ));
+            }
+        };
+        let result = !suppressed && result.as_boolean().unwrap_or(true);
+
+        // Promises created by the callbacks are resolved here.
+        self.run_jobs();
+
+        Ok(result)
+    }
+
+    // Run the promise jobs one by one, as the plugin owning the job's realm.
+    // The runaway job is faulted, and the other jobs still run.
+    fn run_jobs(&mut self) {
+        while let Some(job) = self.job_queue.pop() {
+            // The plugins loaded from one script share the realm. The first one is blamed.
+            let plugin_id = job.realm().and_then(|realm| {
+                self.plugin_realms
+                    .values()
+                    .find(|plugin_realm| &plugin_realm.realm == realm)
+                    .and_then(|plugin_realm| plugin_realm.plugin_ids.first().cloned())
+            });
+            if let Some(plugin_id) = &plugin_id {
+                if let Err(err) = self.call_driver_function(
+                    "$$enterPlugin",
+                    &[JsValue::from(js_string!(plugin_id.as_str()))],
+                ) {
+                    log::error!("Cannot enter {}: {:?}", plugin_id, err);
+                }
+            }
+
+            take_aborted();
+            let result = job.call(&mut self.context);
+            if let Err(err) = result {
+                self.fault_current_plugin(format!("{}", err));
+            } else if take_aborted() {
+                self.fault_current_plugin(
+                    "The promise job was aborted by the runtime limits".to_string(),
+                );
+            } else if plugin_id.is_some() {
+                if let Err(err) = self.call_driver_function("$$leavePlugin", &[]) {
+                    log::error!("Cannot leave the plugin: {:?}", err);
+                }
+            }
+        }
+    }
+
+    fn call_driver_function(&mut self, name: &str, args: &[JsValue]) -> anyhow::Result<JsValue> {
+        let function = self.driver_function(name)?;
+        function
+            .call(&JsValue::undefined(), args, &mut self.context)
+            .map_err(|err| anyhow!("Cannot call {} as JsFunction: {:?}", name, err))
+    }
+
+    fn fault_current_plugin(&mut self, message: String) {
+        let result = self.driver_function("$$faultCurrentPlugin").and_then(|f| {
+            f.call(
                 &JsValue::undefined(),
-                &[
-                    JsValue::from(js_key_event),
-                    JsValue::Boolean(needs_config_reload),
-                ],
+                &[JsValue::from(js_string!(message))],
                 &mut self.context,
             )
-            .map_err(|err| anyhow!("Cannot call $$invokeEvent as JsFunction: {:?}", err))?;
-        let result = result.as_boolean().unwrap_or(true);
-        Ok(result)
+            .map_err(|err| anyhow!("Cannot call $$faultCurrentPlugin: {:?}", err))
+        });
+        match result {
+            Ok(plugin_id) => {
+                if !plugin_id.is_undefined() {
+                    log::error!("Aborted the plugin: {}", plugin_id.display());
+                }
+            }
+            Err(err) => log::error!("Cannot fault the current plugin: {:?}", err),
+        }
+    }
+
+    // Call this method periodically from the run loop.
+    // This method fires expired timers and runs pending promise jobs.
+    pub fn tick(&mut self) -> anyhow::Result<()> {
+        let run_timers = self.driver_function("$$runTimers")?;
+        if let Err(err) = run_timers.call(&JsValue::undefined(), &[], &mut self.context) {
+            // The timer runs as the plugin that set it. The other timers run on the next tick.
+            self.fault_current_plugin(format!("{}", err));
+            return Err(anyhow!("Cannot call $$runTimers as JsFunction: {:?}", err));
+        }
+
+        self.run_jobs();
+        Ok(())
     }
 
     fn get_js_operations(&mut self) -> Vec<JsOperation> {
@@ -290,31 +621,56 @@ impl JS {
         result
     }
 
-    fn unload_plugin(&mut self, plugin_id: String) -> anyhow::Result<()> {
+    pub(crate) fn unload_plugin(&mut self, plugin_id: String) -> anyhow::Result<()> {
         log::info!("Trying to unload plugin: {}", plugin_id);
 
-        let unload_plugin = self
-            .context
-            .global_object()
-            .get(js_string!("$$unloadPlugin"), &mut self.context)
-            .map_err(|err| anyhow!("Cannot get $$unloadPlugin: {:?}", err))?;
-        let unload_plugin = JsFunction::try_from_js(&unload_plugin, &mut self.context)
-            .map_err(|err| anyhow!("Cannot get $$unloadPlugin as JsFunction: {:?}", err))?;
+        let unload_plugin = self.driver_function("$$unloadPlugin")?;
         unload_plugin
             .call(
                 &JsValue::undefined(),
-                &[JsValue::from(js_string!(plugin_id))],
+                &[JsValue::from(js_string!(plugin_id.clone()))],
                 &mut self.context,
             )
             .map_err(|err| anyhow!("Cannot call $$unloadPlugin as JsFunction: {:?}", err))?;
+
+        // Drop the realm with the last plugin in it. The plugin's state is freed with it.
+        self.plugin_id2filename.remove(&plugin_id);
+        let module_loader = &self.module_loader;
+        self.plugin_realms.retain(|filename, plugin_realm| {
+            plugin_realm.plugin_ids.retain(|id| *id != plugin_id);
+            if plugin_realm.plugin_ids.is_empty() {
+                log::info!("Dropping the realm for {}", filename);
+                module_loader.forget_plugin(filename);
+                false
+            } else {
+                true
+            }
+        });
         Ok(())
     }
 
-    fn build_key_event(
-        &mut self,
-        event: &Event,
-        cg_event_type: CGEventType,
-    ) -> anyhow::Result<JsObject> {
+    fn reload_config(&mut self) -> anyhow::Result<()> {
+        let reload_config = self.driver_function("$$reloadConfig")?;
+        reload_config
+            .call(&JsValue::undefined(), &[], &mut self.context)
+            .map_err(|err| anyhow!("Cannot call $$reloadConfig as JsFunction: {:?}", err))?;
+        Ok(())
+    }
+
+    // Enable or disable the plugin, without reloading the scripts.
+    fn set_plugin_enabled(&mut self, plugin_id: &str, enabled: bool) -> anyhow::Result<()> {
+        let set_plugin_enabled = self.driver_function("$$setPluginEnabled")?;
+        set_plugin_enabled
+            .call(
+                &JsValue::undefined(),
+                &[JsValue::from(js_string!(plugin_id)), JsValue::from(enabled)],
+                &mut self.context,
+            )
+            .map_err(|err| anyhow!("Cannot call $$setPluginEnabled as JsFunction: {:?}", err))?;
+        Ok(())
+    }
+
+    fn build_key_event(&mut self, event: &Event, suppressed: bool) -> anyhow::Result<JsObject> {
         let key_event = JsObject::with_object_proto(self.context.intrinsics());
 
         fn set<K, V>(js: &mut JS, key_event: &JsObject, key: K, value: V) -> anyhow::Result<()>
@@ -342,13 +698,29 @@ impl JS {
             JsValue::from(event.keycode),
         )?;
 
-        if cg_event_type == CGEventType_kCGEventFlagsChanged {
+        if event.event_type == "flagsChanged" {
             set(
                 self,
                 &key_event,
                 js_string!("flags"),
                 JsValue::from(event.flags),
             )?;
+        } else {
+            set(
+                self,
+                &key_event,
+                js_string!("autorepeat"),
+                JsValue::Boolean(event.autorepeat),
+            )?;
+        }
+
+        if suppressed {
+            set(
+                self,
+                &key_event,
+                js_string!("suppressed"),
+                JsValue::Boolean(true),
+            )?;
         }
 
         Ok(key_event)
@@ -359,41 +731,227 @@ impl JS {
         self.eval(driver_src.to_string())
     }
 
-    fn load_bundled(&mut self) -> anyhow::Result<JsValue> {
+    fn load_bundled(&mut self) -> anyhow::Result<Vec<String>> {
         let src = include_str!("../js/dynamic-macro.js");
-        self.eval(src.to_string())
+        self.load_in_new_realm(BUNDLED_DYNAMIC_MACRO, src.to_string())
+    }
+
+    // Evaluate the script in the new realm.
+    // Returns the plugin ids registered by the script.
+    pub(crate) fn load_in_new_realm(
+        &mut self,
+        filename: &str,
+        src: String,
+    ) -> anyhow::Result<Vec<String>> {
+        self.load_snippet(PluginSnippet::new(filename.to_string(), src))
+    }
+
+    // Evaluate the script, or the scripts of the plugin package, in the new realm.
+    fn load_snippet(&mut self, snippet: PluginSnippet) -> anyhow::Result<Vec<String>> {
+        let filename = snippet.filename.as_str();
+        let last_loaded_plugins = self.loaded_plugins()?;
+
+        let realm = self
+            .context
+            .create_realm()
+            .map_err(|err| anyhow!("Cannot create realm for {}: {:?}", filename, err))?;
+        let driver_realm = self.context.enter_realm(realm.clone());
+        let result = self
+            .install_api()
+            .and_then(|_| self.install_driver_api())
+            .and_then(|_| match &snippet.package_dir {
+                Some(package_dir) => self.install_package_api(package_dir),
+                None => Ok(()),
+            })
+            .and_then(|_| {
+                for (path, src) in &snippet.preludes {
+                    self.eval(src.clone())
+                        .map_err(|err| anyhow!("{}: {:?}", path, err))?;
+                }
+                if snippet.module {
+                    self.eval_module(&snippet)
+                } else {
+                    self.eval(snippet.src.clone()).map(|_| ())
+                }
+            });
+        self.context.enter_realm(driver_realm);
+
+        let current_loaded_plugins = self.loaded_plugins()?;
+        let plugin_ids: Vec<String> = current_loaded_plugins
+            .difference(&last_loaded_plugins)
+            .cloned()
+            .collect();
+        self.plugin_realms.insert(
+            filename.to_string(),
+            PluginRealm {
+                realm,
+                plugin_ids: plugin_ids.clone(),
+            },
+        );
+
+        result.map_err(|err| anyhow!("Cannot load {}: {:?}", filename, err))?;
+        Ok(plugin_ids)
     }
 
     fn reload_plugins(&mut self) -> anyhow::Result<()> {
         log::info!("JS::reload_plugins");
+
+        // Unload the user scripts first. Otherwise, the closures of the old scripts remain.
+        let plugin_ids: Vec<String> = self
+            .plugin_realms
+            .iter()
+            .filter(|(filename, _)| filename.as_str() != BUNDLED_DYNAMIC_MACRO)
+            .flat_map(|(_, plugin_realm)| plugin_realm.plugin_ids.clone())
+            .collect();
+        for plugin_id in plugin_ids {
+            self.unload_plugin(plugin_id)?;
+        }
+        self.plugin_realms
+            .retain(|filename, _| filename.as_str() == BUNDLED_DYNAMIC_MACRO);
+
         self.load_user_scripts()
     }
 
+    // Unload all plugins, including the bundled ones. Only the driver remains.
+    pub fn unload_all_plugins(&mut self) -> anyhow::Result<()> {
+        for plugin_id in self.loaded_plugins()? {
+            self.unload_plugin(plugin_id)?;
+        }
+        Ok(())
+    }
+
+    // Unload all plugins, and keep them unloaded. Reloading the plugins doesn't load them.
+    pub fn enter_safe_mode(&mut self) -> anyhow::Result<()> {
+        self.safe_mode = true;
+        self.unload_all_plugins()
+    }
+
     pub fn load_user_scripts(&mut self) -> anyhow::Result<()> {
+        if self.safe_mode {
+            log::info!("The plugins are not loaded in safe mode");
+            return Ok(());
+        }
         log::info!("Trying to load plugins");
 
         if let Some(plugins) = &self.plugins {
-            let plugin_snippets = plugins.read_user_scripts()?;
-
-            let mut last_loaded_plugins = self.loaded_plugins()?;
+            let plugin_snippets: Vec<_> = plugins
+                .read_user_scripts()?
+                .into_iter()
+                .map(|snippet| {
+                    let manifest = plugins.read_manifest(&snippet);
+                    (snippet, manifest)
+                })
+                .collect();
+            let mut installed_ids = plugins.installed_plugin_ids()?;
+            installed_ids.extend(BUNDLED_PLUGIN_IDS.iter().map(|id| id.to_string()));
+            let config_path = plugins.config_path().to_path_buf();
+            self.migrate_plugin_configs(
+                &config_path,
+                &installed_ids,
+                plugin_snippets
+                    .iter()
+                    .filter_map(|(_, manifest)| manifest.as_ref().ok().and_then(Option::as_ref)),
+            );
 
-            for plugin_snippet in plugin_snippets {
-                let filename = plugin_snippet.filename.as_str();
+            let mut errors = Vec::new();
+            for (mut plugin_snippet, manifest) in plugin_snippets {
+                let filename = plugin_snippet.filename.clone();
 
-                if let Err(err) = self.eval(plugin_snippet.src) {
-                    log::error!("Cannot load {}: {:?}", plugin_snippet.filename, err)
+                let manifest = match manifest {
+                    Ok(manifest) => manifest,
+                    Err(err) => {
+                        // Don't run the script with the broken manifest.
+                        log::error!("Cannot load {}: {:?}", filename, err);
+                        errors.push(format!("{}: {}", filename, err));
+                        continue;
+                    }
+                };
+                if let Some(manifest) = &manifest {
+                    self.plugin_id2filename
+                        .insert(manifest.id.clone(), filename.clone());
+                    plugin_snippet.module |= manifest.is_module();
                 }
 
-                let current_loaded_plugins = self.loaded_plugins()?;
+                if let Err(err) = self
+                    .load_snippet(plugin_snippet)
+                    .and_then(|_| self.check_manifest(&filename, &manifest))
+                {
+                    log::error!("Cannot load {}: {:?}", filename, err);
+                    errors.push(format!("{}: {}", filename, err));
+                }
 
-                let diff = current_loaded_plugins.difference(&last_loaded_plugins);
-                for plugin_id in diff {
-                    self.plugin_id2filename
-                        .insert(plugin_id.to_string(), filename.to_string());
+                // The script may register some plugins before the error.
+                if let Some(plugin_realm) = self.plugin_realms.get(&filename) {
+                    for plugin_id in &plugin_realm.plugin_ids {
+                        self.plugin_id2filename
+                            .insert(plugin_id.to_string(), filename.clone());
+                    }
                 }
+            }
+            // The other scripts are loaded anyway.
+            if !errors.is_empty() {
+                return Err(anyhow!("Cannot load plugins: {}", errors.join(", ")));
+            }
+        }
+        Ok(())
+    }
+
+    // Carry over the settings of the renamed plugins, before the plugins read them.
+    fn migrate_plugin_configs<'a>(
+        &mut self,
+        config_path: &Path,
+        installed_ids: &HashSet<String>,
+        manifests: impl Iterator<Item = &'a PluginManifest>,
+    ) {
+        let mut config = match AppConfig::load_at(config_path) {
+            Ok(config) => config,
+            Err(err) => {
+                log::error!("Cannot load configuration: {:?}", err);
+                return;
+            }
+        };
+        let mut changed = false;
+        for manifest in manifests {
+            changed |= config.migrate_plugin(
+                &manifest.id,
+                &manifest.previous_ids,
+                &manifest.renamed_config_keys,
+                installed_ids,
+            );
+        }
+        if !changed {
+            return;
+        }
+        if let Err(err) = config
+            .save_at(config_path)
+            .and_then(|_| self.reload_config())
+        {
+            log::error!("Cannot migrate plugin settings: {:?}", err);
+        }
+    }
 
-                last_loaded_plugins = current_loaded_plugins;
+    // The plugins registered by the script must match with its manifest.
+    fn check_manifest(
+        &mut self,
+        filename: &str,
+        manifest: &Option<PluginManifest>,
+    ) -> anyhow::Result<()> {
+        let (manifest, plugin_ids) = match (manifest, self.plugin_realms.get(filename)) {
+            (Some(manifest), Some(plugin_realm)) => (manifest, plugin_realm.plugin_ids.clone()),
+            _ => return Ok(()),
+        };
+        let registered: Vec<(String, String)> = self
+            .get_config_schema()?
+            .plugins
+            .into_iter()
+            .filter(|plugin| plugin_ids.contains(&plugin.id))
+            .map(|plugin| (plugin.id, plugin.name))
+            .collect();
+        if let Err(err) = manifest.check_registered(&registered) {
+            for plugin_id in plugin_ids {
+                self.unload_plugin(plugin_id)?;
             }
+            return Err(err);
         }
         Ok(())
     }
@@ -409,13 +967,7 @@ impl JS {
     }
 
     pub fn get_config_schema(&mut self) -> anyhow::Result<ConfigSchemaList> {
-        let get_config_schema = self
-            .context
-            .global_object()
-            .get(js_string!("$$getConfigSchema"), &mut self.context)
-            .map_err(|err| anyhow!("Cannot get $$getConfigSchema: {:?}", err))?;
-        let get_config_schema = JsFunction::try_from_js(&get_config_schema, &mut self.context)
-            .map_err(|err| anyhow!("Cannot get $$getConfigSchema as JsFunction: {:?}", err))?;
+        let get_config_schema = self.driver_function("$$getConfigSchema")?;
 
         let result = get_config_schema
             .call(&JsValue::undefined(), &[], &mut self.context)
@@ -429,6 +981,15 @@ impl JS {
     }
 }
 
+/// Result of the code evaluated from the REPL.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EvalResult {
+    pub value: Option<String>,
+    pub error: Option<String>,
+    // The console output while evaluating the code.
+    pub console: Vec<TimedLogMessage>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ConfigSchemaList {
     pub plugins: Vec<ConfigSchema>,
@@ -437,9 +998,9 @@ pub struct ConfigSchemaList {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ConfigSchema {
     pub id: String,
-    name: String,
+    pub name: String,
     description: String,
-    config: Vec<HashMap<String, String>>,
+    pub config: Vec<ConfigItem>,
 }
 
 #[cfg(test)]
@@ -456,6 +1017,504 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_query_operations() -> anyhow::Result<()> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut js = JS::new(Some(rx), None, None)?;
+
+        let (reply, schema_rx) = std::sync::mpsc::channel();
+        tx.send(JsOperation::GetConfigSchema { reply })?;
+        let (reply, filename_rx) = std::sync::mpsc::channel();
+        tx.send(JsOperation::GetFilenameByPluginId {
+            plugin_id: "unknown".to_string(),
+            reply,
+        })?;
+        js.process_js_operations();
+
+        let schema = schema_rx.recv()??;
+        assert_eq!(schema.plugins.first().unwrap().id, "builtin.dynamicmacro");
+        assert_eq!(filename_rx.recv()?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_eval_in_repl() -> anyhow::Result<()> {
+        let mut js = JS::new(None, None, None)?;
+        js.load_in_new_realm(
+            "counter.js",
+            r#"
+            var count = 3;
+            registerPlugin("counter", "Counter", "", function () { return true; }, []);
+            "#
+            .to_string(),
+        )?;
+
+        let result = js.eval_in_repl("console.log('hello'); 1 + 2", None);
+        assert_eq!(result.value, Some("3".to_string()));
+        assert_eq!(result.console.len(), 1);
+
+        // the plugin's globals are visible only in its scope.
+        let result = js.eval_in_repl("count", Some("counter"));
+        assert_eq!(result.value, Some("3".to_string()));
+        let result = js.eval_in_repl("count", None);
+        assert!(result.error.is_some());
+
+        let result = js.eval_in_repl("1", Some("unknown"));
+        assert!(result.error.is_some());
+
+        // the promise job is blamed on the plugin.
+        js.load_in_new_realm(
+            "repl-job.js",
+            r#"registerPlugin("repl-job", "REPL job", "", function () { return true; }, []);"#
+                .to_string(),
+        )?;
+        js.eval_in_repl(
+            "Promise.resolve().then(() => { while (true) {} })",
+            Some("repl-job"),
+        );
+        assert!(is_faulted("repl-job"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_manifest_mismatch() -> anyhow::Result<()> {
+        let temp_dir = tempfile::TempDir::with_prefix("manifest")?;
+        let plugins = Plugins::new_with_basedir(temp_dir.path().to_str().unwrap().to_string());
+        plugins.add("good".to_string(), "Good".to_string(), "".to_string())?;
+        plugins.write(
+            "bad.js".to_string(),
+            r#"// ==KeyScriptenPlugin==
+// {"id": "bad", "name": "Bad"}
+// ==/KeyScriptenPlugin==
+registerPlugin("other", "Other", "", function () { return true; }, []);
+"#
+            .to_string(),
+        )?;
+
+        let mut js = JS::new(None, None, Some(plugins))?;
+        assert!(js.load_user_scripts().is_err());
+        let loaded = js.loaded_plugins()?;
+        assert!(loaded.contains("good"));
+        assert!(!loaded.contains("other"));
+        assert_eq!(
+            js.get_filename_by_plugin_id(&"good".to_string()),
+            Some("good.js".to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_plugin_package() -> anyhow::Result<()> {
+        let temp_dir = tempfile::TempDir::with_prefix("package")?;
+        let plugins = Plugins::new_with_basedir(temp_dir.path().to_str().unwrap().to_string());
+        let file = |path: &str, content: &str| crate::plugin::PackageFile {
+            path: path.to_string(),
+            content: content.as_bytes().to_vec(),
+        };
+        plugins.write_package(
+            "greeting".to_string(),
+            vec![
+                file(
+                    "manifest.json",
+                    r#"{"id": "greeting", "name": "Greeting", "scripts": ["util.js"]}"#,
+                ),
+                file("util.js", "function upper(s) { return s.toUpperCase(); }"),
+                file(
+                    "index.js",
+                    r#"
+                    var words = upper(readPluginResource("data/words.txt"));
+                    registerPlugin("greeting", "Greeting", "", function () { return true; }, []);
+                    "#,
+                ),
+                file("data/words.txt", "hello"),
+            ],
+        )?;
+
+        let mut js = JS::new(None, None, Some(plugins))?;
+        js.load_user_scripts()?;
+        let result = js.eval_in_repl("words", Some("greeting"));
+        assert_eq!(result.value, Some("\"HELLO\"".to_string()));
+
+        // can't read out of the package.
+        let result = js.eval_in_repl("readPluginResource('../greeting.js')", Some("greeting"));
+        assert!(result.error.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_module_plugin() -> anyhow::Result<()> {
+        let temp_dir = tempfile::TempDir::with_prefix("module")?;
+        let plugins_dir = temp_dir.path().join("plugins");
+        let plugins = Plugins::new_with_basedir(plugins_dir.to_str().unwrap().to_string());
+        fs::create_dir_all(plugins.lib_dir())?;
+        fs::write(
+            plugins.lib_dir().join("math.js"),
+            "export function double(n) { return n * 2; }",
+        )?;
+        fs::write(temp_dir.path().join("outside.js"), "export const x = 1;")?;
+        plugins.write(
+            "counter.mjs".to_string(),
+            r#"
+            import { sendKeys } from "keyscripten:send";
+            import { double } from "lib/math.js";
+            globalThis.result = double(21) + typeof sendKeys;
+            registerPlugin("counter", "Counter", "", function () { return true; }, []);
+            "#
+            .to_string(),
+        )?;
+        plugins.write(
+            "escape.mjs".to_string(),
+            r#"
+            import { x } from "../outside.js";
+            registerPlugin("escape", "Escape", "", function () { return true; }, []);
+            "#
+            .to_string(),
+        )?;
+
+        // registers the plugin after the manifest is checked.
+        plugins.write(
+            "tla.mjs".to_string(),
+            r#"
+            await sleep(10);
+            registerPlugin("tla", "TLA", "", function () { return true; }, []);
+            "#
+            .to_string(),
+        )?;
+        // the scripts can't import the modules, even after the module plugins are loaded.
+        plugins.write(
+            "zz-dynamic.js".to_string(),
+            r#"
+            var imported = "pending";
+            import("keyscripten:send").then(() => imported = "ok", () => imported = "rejected");
+            registerPlugin("dynamic", "Dynamic", "", function () { return true; }, []);
+            "#
+            .to_string(),
+        )?;
+
+        let mut js = JS::new(None, None, Some(plugins))?;
+        let err = js.load_user_scripts().unwrap_err();
+        assert!(format!("{:?}", err).contains("top level"));
+        let result = js.eval_in_repl("result", Some("counter"));
+        assert_eq!(result.value, Some("\"42function\"".to_string()));
+        assert!(!js.loaded_plugins()?.contains("escape"));
+        assert!(!js.loaded_plugins()?.contains("tla"));
+
+        js.run_jobs();
+        let result = js.eval_in_repl("imported", Some("dynamic"));
+        assert_eq!(result.value, Some("\"rejected\"".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_tick_runs_timers_and_jobs() -> anyhow::Result<()> {
+        let mut js = JS::new(None, None, None)?;
+        js.eval(
+            r#"
+            var $$fired = [];
+            setTimeout(() => $$fired.push("timer"), 0);
+            (async () => {
+                await sleep(0);
+                $$fired.push("async");
+            })();
+            "#
+            .to_string(),
+        )?;
+        js.tick()?;
+        js.tick()?;
+        let fired = js.eval("$$fired.join(',')".to_string())?;
+        let fired = fired.to_string(&mut js.context).unwrap();
+        assert_eq!(fired.to_std_string_escaped(), "timer,async");
+        Ok(())
+    }
+
+    #[test]
+    fn test_typed_config() -> anyhow::Result<()> {
+        let mut js = JS::new(None, None, None)?;
+        js.load_in_new_realm(
+            "typed.js",
+            r#"
+            registerPlugin("typed", "Typed", "", function () { return true; }, [
+                {"name": "flag", "type": "boolean", "default": false},
+                {"name": "size", "type": "integer", "default": "0", "min": 0},
+                {"name": "ratio", "type": "float", "default": 0.5},
+                {"name": "mode", "type": "enum", "choices": ["fast", "slow"], "default": "slow"},
+                {"name": "leader", "type": "key", "default": "escape"},
+                {"name": "keys", "type": "hotkey_list", "default": ["C-a", "C-e"]},
+                {"name": "note", "type": "text"},
+            ]);
+            "#
+            .to_string(),
+        )?;
+        let config = js.eval(
+            r#"
+            const c = $$CONFIG["typed"];
+            [c.flag, c.size, c.ratio, c.mode, c.leader, c.keys.length, c.note].join(",")
+            "#
+            .to_string(),
+        )?;
+        let config = config.to_string(&mut js.context).unwrap();
+        assert_eq!(config.to_std_string_escaped(), "false,0,0.5,slow,53,2,");
+
+        // The invalid schema is rejected on registration.
+        assert!(js
+            .load_in_new_realm(
+                "broken.js",
+                r#"
+                registerPlugin("broken", "Broken", "", function () { return true; }, [
+                    {"name": "mode", "type": "enum", "choices": ["a"], "default": "b"},
+                ]);
+                "#
+                .to_string(),
+            )
+            .is_err());
+        assert!(!js.loaded_plugins()?.contains("broken"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_plugin_realm() -> anyhow::Result<()> {
+        let mut js = JS::new(None, None, None)?;
+        js.load_in_new_realm(
+            "evil.js",
+            r#"
+            var $$invokeEvent = function () { return false; };
+            registerPlugin.evil = true;
+            registerPlugin("evil", "Evil", "", function () { return true; }, []);
+            "#
+            .to_string(),
+        )?;
+        assert!(js.loaded_plugins()?.contains("evil"));
+
+        // the driver is not affected.
+        let value =
+            js.eval("$$invokeEvent.length === 1 && registerPlugin.evil === undefined".to_string())?;
+        assert_eq!(value, JsValue::Boolean(true));
+
+        js.unload_plugin("evil".to_string())?;
+        assert!(!js.loaded_plugins()?.contains("evil"));
+        assert!(!js.plugin_realms.contains_key("evil.js"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_plugin_enabled() -> anyhow::Result<()> {
+        let mut js = JS::new(None, None, None)?;
+        js.load_in_new_realm(
+            "drop.js",
+            r#"
+            registerPlugin("drop", "Drop", "", function () { return false; }, []);
+            "#
+            .to_string(),
+        )?;
+        let event = Event {
+            event_type: "keyDown".to_string(),
+            keycode: 17,
+            flags: 0,
+            autorepeat: false,
+            timestamp: 0,
+        };
+        assert!(!js.send_event(&event, false)?);
+
+        js.set_plugin_enabled("drop", false)?;
+        assert!(js.send_event(&event, false)?);
+
+        js.set_plugin_enabled("drop", true)?;
+        assert!(!js.send_event(&event, false)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unload_all_plugins() -> anyhow::Result<()> {
+        let mut js = JS::new(None, None, None)?;
+        assert!(!js.loaded_plugins()?.is_empty());
+        js.unload_all_plugins()?;
+        assert!(js.loaded_plugins()?.is_empty());
+        assert!(js.plugin_realms.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate_plugin_configs() -> anyhow::Result<()> {
+        let temp_dir = tempfile::TempDir::with_prefix("migrate")?;
+        let plugins_dir = temp_dir.path().join("plugins");
+        fs::create_dir(&plugins_dir)?;
+        let config_path = temp_dir.path().join("config.json");
+        fs::write(
+            &config_path,
+            r#"{"plugins": {"old": {"enabled": false, "config": {}}}}"#,
+        )?;
+
+        let plugins = Plugins::new_with_basedir(plugins_dir.to_str().unwrap().to_string());
+        assert_eq!(plugins.config_path(), config_path);
+        plugins.write(
+            "new.js".to_string(),
+            r#"registerPlugin("new", "New", "", function () { return true; }, []);"#.to_string(),
+        )?;
+        fs::write(
+            plugins_dir.join("new.json"),
+            r#"{"id": "new", "name": "New", "previous_ids": ["old"]}"#,
+        )?;
+
+        let mut js = JS::new(None, None, Some(plugins))?;
+        js.load_user_scripts()?;
+
+        // the settings are moved in the config.json next to the plugins directory.
+        let config = AppConfig::load_at(&config_path)?;
+        let config_plugins = config.plugins.unwrap();
+        assert!(!config_plugins.contains_key("old"));
+        assert!(!config_plugins["new"].enabled);
+        Ok(())
+    }
+
+    #[test]
+    fn test_safe_mode() -> anyhow::Result<()> {
+        let temp_dir = tempfile::TempDir::with_prefix("safe_mode")?;
+        let plugins = Plugins::new_with_basedir(temp_dir.path().to_str().unwrap().to_string());
+        plugins.write(
+            "crasher.js".to_string(),
+            r#"registerPlugin("crasher", "Crasher", "", function () { return true; }, []);"#
+                .to_string(),
+        )?;
+
+        let mut js = JS::new(None, None, Some(plugins))?;
+        js.enter_safe_mode()?;
+        js.load_user_scripts()?;
+        // saving the plugin reloads the plugins.
+        js.reload_plugins()?;
+        assert!(js.loaded_plugins()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_runaway_plugin() -> anyhow::Result<()> {
+        let mut js = JS::new(None, None, None)?;
+        js.load_in_new_realm(
+            "runaway.js",
+            r#"
+            registerPlugin("runaway", "Runaway", "", function () {
+                while (true) {}
+            }, []);
+            "#
+            .to_string(),
+        )?;
+
+        let invoke_event = js.driver_function("$$invokeEvent")?;
+        let event = js.eval("({type: 'keyDown', keycode: 0})".to_string())?;
+        let result = invoke_event.call(
+            &JsValue::undefined(),
+            &[event.clone(), JsValue::Boolean(false)],
+            &mut js.context,
+        );
+        assert!(result.is_err());
+        js.fault_current_plugin("aborted".to_string());
+
+        // the plugin is skipped after that.
+        let result = invoke_event
+            .call(
+                &JsValue::undefined(),
+                &[event, JsValue::Boolean(false)],
+                &mut js.context,
+            )
+            .map_err(|err| anyhow!("{:?}", err))?;
+        assert_eq!(result, JsValue::Boolean(true));
+        Ok(())
+    }
+
+    fn is_faulted(plugin_id: &str) -> bool {
+        crate::quarantine::get_plugin_faults()
+            .iter()
+            .any(|fault| fault.plugin_id == plugin_id && fault.quarantined)
+    }
+
+    #[test]
+    fn test_runaway_timer() -> anyhow::Result<()> {
+        let mut js = JS::new(None, None, None)?;
+        js.load_in_new_realm(
+            "runaway-timer.js",
+            r#"
+            registerPlugin("runaway-timer", "Runaway timer", "", function () {
+                setTimeout(() => { while (true) {} }, 0);
+                return true;
+            }, []);
+            "#
+            .to_string(),
+        )?;
+        let event = Event {
+            event_type: "keyDown".to_string(),
+            keycode: 17,
+            flags: 0,
+            autorepeat: false,
+            timestamp: 0,
+        };
+        assert!(js.send_event(&event, false)?);
+        assert!(!is_faulted("runaway-timer"));
+
+        // the timer is aborted, and the plugin that set it is blamed.
+        assert!(js.tick().is_err());
+        assert!(is_faulted("runaway-timer"));
+
+        // the plugin doesn't set the timer anymore.
+        assert!(js.send_event(&event, false)?);
+        js.tick()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_unloaded_plugin_timer() -> anyhow::Result<()> {
+        let mut js = JS::new(None, None, None)?;
+        js.load_in_new_realm(
+            "unloaded-timer.js",
+            r#"
+            registerPlugin("unloaded-timer", "Unloaded timer", "", function () {
+                setTimeout(() => { while (true) {} }, 0);
+                return true;
+            }, []);
+            "#
+            .to_string(),
+        )?;
+        let event = Event {
+            event_type: "keyDown".to_string(),
+            keycode: 17,
+            flags: 0,
+            autorepeat: false,
+            timestamp: 0,
+        };
+        assert!(js.send_event(&event, false)?);
+        js.unload_plugin("unloaded-timer".to_string())?;
+
+        // the timer is removed with the plugin, so it never runs.
+        let value = js.eval("$$TIMERS.length === 0".to_string())?;
+        assert_eq!(value, JsValue::Boolean(true));
+        js.tick()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_runaway_promise_job() -> anyhow::Result<()> {
+        let mut js = JS::new(None, None, None)?;
+        js.load_in_new_realm(
+            "runaway-job.js",
+            r#"
+            registerPlugin("runaway-job", "Runaway job", "", function () {
+                Promise.resolve().then(() => { while (true) {} });
+                return true;
+            }, []);
+            "#
+            .to_string(),
+        )?;
+        let event = Event {
+            event_type: "keyDown".to_string(),
+            keycode: 17,
+            flags: 0,
+            autorepeat: false,
+            timestamp: 0,
+        };
+        // the reaction runs as the plugin owning the realm.
+        assert!(js.send_event(&event, false)?);
+        assert!(is_faulted("runaway-job"));
+        Ok(())
+    }
+
     #[test]
     fn test_eval() -> anyhow::Result<()> {
         let mut js = JS::new(None, None, None)?;
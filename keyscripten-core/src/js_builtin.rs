@@ -1,7 +1,50 @@
 use crate::app_config::AppConfig;
-use crate::send::{send_flags_changed_event, send_keyboard_event};
+use crate::config_schema::parse_config_schema;
+use crate::keycode;
+use crate::modifier_state::{get_modifier_state, release_all_modifiers, resync_modifiers};
+use crate::output::{enqueue, Placement, SyntheticEvent};
+use crate::pressed_keys::{get_pressed_keys, is_pressed};
+use crate::quarantine::{fault_plugin, record_plugin_error, reset_plugin_fault};
+use crate::session::{persist_active_plugin, set_active_plugin};
 use apple_sys::CoreGraphics::{CGEventFlags, CGKeyCode};
-use boa_engine::{Context, js_string, JsArgs, JsError, JsNativeError, JsResult, JsString, JsValue};
+use boa_engine::object::builtins::JsArray;
+use boa_engine::{
+    js_string, Context, JsArgs, JsError, JsNativeError, JsObject, JsResult, JsString, JsValue,
+};
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+thread_local! {
+    // The plugin code running on this thread is aborted after this.
+    static BUDGET_DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+    // Boa swallows the runtime limit errors in the promise jobs. They are recorded here.
+    static ABORTED: Cell<bool> = const { Cell::new(false) };
+}
+
+// Called before the plugin-facing native function runs.
+// The runtime limit error can't be caught by the plugin, so the callback is aborted.
+pub(crate) fn check_budget() -> JsResult<()> {
+    match BUDGET_DEADLINE.get() {
+        Some(deadline) if Instant::now() > deadline => {
+            ABORTED.set(true);
+            persist_active_plugin();
+            Err(JsNativeError::runtime_limit()
+                .with_message("The callback exceeded the execution budget")
+                .into())
+        }
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn mark_aborted() {
+    ABORTED.set(true);
+}
+
+// Returns true if the plugin code was aborted, or ran out of the budget, since the last call.
+pub(crate) fn take_aborted() -> bool {
+    let exceeded = matches!(BUDGET_DEADLINE.get(), Some(deadline) if Instant::now() > deadline);
+    ABORTED.replace(false) || exceeded
+}
 
 pub struct JsBuiltin {}
 
@@ -12,12 +55,15 @@ impl JsBuiltin {
         context: &mut Context,
     ) -> JsResult<JsValue> {
         let flags: &JsValue = args.get_or_undefined(0);
+        let (placement, delay) = Self::parse_send_options(args.get_or_undefined(1), context)?;
 
-        if let Err(err) = send_flags_changed_event(flags.to_i32(context).unwrap() as CGEventFlags) {
-            return Err(JsNativeError::typ()
-                .with_message(format!("Cannot run send_flags_changed_event: {:?}", err))
-                .into());
-        }
+        enqueue(
+            SyntheticEvent::FlagsChanged {
+                flags: flags.to_i32(context)? as CGEventFlags,
+            },
+            placement,
+            delay,
+        );
 
         Ok(JsValue::undefined())
     }
@@ -30,20 +76,290 @@ impl JsBuiltin {
         let keycode: &JsValue = args.get_or_undefined(0);
         let flags: &JsValue = args.get_or_undefined(1);
         let pressed: &JsValue = args.get_or_undefined(2);
+        let (placement, delay) = Self::parse_send_options(args.get_or_undefined(3), context)?;
 
-        if let Err(err) = send_keyboard_event(
-            keycode.to_i32(context).unwrap() as CGKeyCode,
-            flags.to_i32(context).unwrap() as CGEventFlags,
-            pressed.to_boolean(),
-        ) {
+        enqueue(
+            SyntheticEvent::Keyboard {
+                keycode: keycode.to_i32(context)? as CGKeyCode,
+                flags: flags.to_i32(context)? as CGEventFlags,
+                keydown: pressed.to_boolean(),
+            },
+            placement,
+            delay,
+        );
+
+        Ok(JsValue::undefined())
+    }
+
+    // sendKeys([{keycode, flags, pressed, delay}, ...], options)
+    // If `pressed` is omitted, the key is pressed and released.
+    pub fn send_keys(
+        _this: &JsValue,
+        args: &[JsValue],
+        context: &mut Context,
+    ) -> JsResult<JsValue> {
+        let Some(keys) = args.get_or_undefined(0).as_object() else {
             return Err(JsNativeError::typ()
-                .with_message(format!("Cannot run send_keyboard_event: {:?}", err))
+                .with_message("first argument of the sendKeys should be an array.")
                 .into());
+        };
+        let keys = JsArray::from_object(keys.clone())?;
+        let (placement, default_delay) =
+            Self::parse_send_options(args.get_or_undefined(1), context)?;
+
+        for i in 0..keys.length(context)? {
+            let key = keys.get(i, context)?;
+            let Some(key) = key.as_object() else {
+                return Err(JsNativeError::typ()
+                    .with_message(format!("sendKeys: element {} should be an object.", i))
+                    .into());
+            };
+            let keycode = key.get(js_string!("keycode"), context)?.to_i32(context)? as CGKeyCode;
+            let flags = key.get(js_string!("flags"), context)?;
+            let flags = if flags.is_undefined() {
+                0
+            } else {
+                flags.to_i32(context)? as CGEventFlags
+            };
+            let delay = key.get(js_string!("delay"), context)?;
+            let delay = if delay.is_undefined() {
+                default_delay
+            } else {
+                Duration::from_millis(delay.to_u32(context)? as u64)
+            };
+
+            let pressed = key.get(js_string!("pressed"), context)?;
+            let states = if pressed.is_undefined() {
+                vec![true, false]
+            } else {
+                vec![pressed.to_boolean()]
+            };
+            for (n, keydown) in states.into_iter().enumerate() {
+                enqueue(
+                    SyntheticEvent::Keyboard {
+                        keycode,
+                        flags,
+                        keydown,
+                    },
+                    placement,
+                    if n == 0 { delay } else { Duration::ZERO },
+                );
+            }
         }
 
         Ok(JsValue::undefined())
     }
 
+    // returns {physical, synthetic}
+    pub fn get_modifier_state(
+        _this: &JsValue,
+        _args: &[JsValue],
+        context: &mut Context,
+    ) -> JsResult<JsValue> {
+        let state = get_modifier_state();
+
+        let result = JsObject::with_object_proto(context.intrinsics());
+        result.set(
+            js_string!("physical"),
+            JsValue::from(state.physical),
+            false,
+            context,
+        )?;
+        result.set(
+            js_string!("synthetic"),
+            JsValue::from(state.synthetic),
+            false,
+            context,
+        )?;
+        Ok(JsValue::from(result))
+    }
+
+    pub fn is_pressed(
+        _this: &JsValue,
+        args: &[JsValue],
+        context: &mut Context,
+    ) -> JsResult<JsValue> {
+        let keycode = args.get_or_undefined(0).to_i32(context)?;
+        Ok(JsValue::Boolean(is_pressed(keycode as i64)))
+    }
+
+    pub fn pressed_keys(
+        _this: &JsValue,
+        _args: &[JsValue],
+        context: &mut Context,
+    ) -> JsResult<JsValue> {
+        let keycodes = get_pressed_keys().into_iter().map(JsValue::from);
+        Ok(JsValue::from(JsArray::from_iter(keycodes, context)))
+    }
+
+    pub fn release_all_modifiers(
+        _this: &JsValue,
+        args: &[JsValue],
+        context: &mut Context,
+    ) -> JsResult<JsValue> {
+        let (placement, _) = Self::parse_send_options(args.get_or_undefined(0), context)?;
+        release_all_modifiers(placement);
+        Ok(JsValue::undefined())
+    }
+
+    // called by driver.js, when the plugin threw an exception.
+    pub fn resync_modifiers(
+        _this: &JsValue,
+        _args: &[JsValue],
+        _context: &mut Context,
+    ) -> JsResult<JsValue> {
+        resync_modifiers();
+        Ok(JsValue::undefined())
+    }
+
+    // called by driver.js, when the plugin threw an exception.
+    // returns true if the plugin is quarantined.
+    pub fn report_plugin_error(
+        _this: &JsValue,
+        args: &[JsValue],
+        context: &mut Context,
+    ) -> JsResult<JsValue> {
+        let plugin_id = args
+            .get_or_undefined(0)
+            .to_string(context)?
+            .to_std_string_escaped();
+        let message = args
+            .get_or_undefined(1)
+            .to_string(context)?
+            .to_std_string_escaped();
+        let stack = args.get_or_undefined(2);
+        let stack = if stack.is_null_or_undefined() {
+            None
+        } else {
+            Some(stack.to_string(context)?.to_std_string_escaped())
+        };
+
+        let quarantined = record_plugin_error(plugin_id.as_str(), message, stack);
+        Ok(JsValue::Boolean(quarantined))
+    }
+
+    // called by driver.js, when the plugin exceeded the execution budget.
+    pub fn fault_plugin(
+        _this: &JsValue,
+        args: &[JsValue],
+        context: &mut Context,
+    ) -> JsResult<JsValue> {
+        let plugin_id = args
+            .get_or_undefined(0)
+            .to_string(context)?
+            .to_std_string_escaped();
+        let message = args
+            .get_or_undefined(1)
+            .to_string(context)?
+            .to_std_string_escaped();
+        fault_plugin(plugin_id.as_str(), message);
+        Ok(JsValue::undefined())
+    }
+
+    // called by driver.js, when the plugin was registered.
+    pub fn reset_plugin_fault(
+        _this: &JsValue,
+        args: &[JsValue],
+        context: &mut Context,
+    ) -> JsResult<JsValue> {
+        let plugin_id = args
+            .get_or_undefined(0)
+            .to_string(context)?
+            .to_std_string_escaped();
+        reset_plugin_fault(plugin_id.as_str());
+        Ok(JsValue::undefined())
+    }
+
+    // called by driver.js, around the plugin's callback, timer and promise job.
+    // undefined means no plugin is running. The second argument is the budget in milliseconds.
+    pub fn set_active_plugin(
+        _this: &JsValue,
+        args: &[JsValue],
+        context: &mut Context,
+    ) -> JsResult<JsValue> {
+        let plugin_id = args.get_or_undefined(0);
+        if plugin_id.is_undefined() {
+            set_active_plugin(None);
+            BUDGET_DEADLINE.set(None);
+        } else {
+            set_active_plugin(Some(plugin_id.to_string(context)?.to_std_string_escaped()));
+            let budget_ms = args.get_or_undefined(1).to_number(context)?;
+            BUDGET_DEADLINE.set(Some(
+                Instant::now() + Duration::from_millis(budget_ms as u64),
+            ));
+        }
+        Ok(JsValue::undefined())
+    }
+
+    // called by driver.js, when the plugin is registered.
+    // Throws if the schema is invalid. Returns the schema with the defaults filled.
+    pub fn normalize_config_schema(
+        _this: &JsValue,
+        args: &[JsValue],
+        context: &mut Context,
+    ) -> JsResult<JsValue> {
+        let json = args
+            .get_or_undefined(0)
+            .to_string(context)?
+            .to_std_string_escaped();
+        let schema = parse_config_schema(json.as_str())
+            .map_err(|err| JsNativeError::typ().with_message(format!("{:#}", err)))?;
+        let json = serde_json::to_string(&schema).map_err(|err| {
+            JsNativeError::typ().with_message(format!("Cannot make json: {:?}", err))
+        })?;
+        Ok(JsValue::String(JsString::from(json.as_str())))
+    }
+
+    // called by driver.js, to convert the key name in the config to the keycode.
+    pub fn get_keycode(
+        _this: &JsValue,
+        args: &[JsValue],
+        context: &mut Context,
+    ) -> JsResult<JsValue> {
+        let keyname = args
+            .get_or_undefined(0)
+            .to_string(context)?
+            .to_std_string_escaped();
+        match keycode::get_keycode(keyname.as_str()) {
+            Some(keycode) => Ok(JsValue::Integer(keycode as i32)),
+            None => Err(JsNativeError::typ()
+                .with_message(format!("Unknown key: {}", keyname))
+                .into()),
+        }
+    }
+
+    // options: {placement: "before" | "after", delay: milliseconds}
+    fn parse_send_options(
+        options: &JsValue,
+        context: &mut Context,
+    ) -> JsResult<(Placement, Duration)> {
+        let Some(options) = options.as_object() else {
+            return Ok((Placement::AfterOriginal, Duration::ZERO));
+        };
+
+        let placement = options.get(js_string!("placement"), context)?;
+        let placement = if placement.is_undefined() {
+            Placement::AfterOriginal
+        } else {
+            let placement = placement.to_string(context)?.to_std_string_escaped();
+            Placement::from_str(placement.as_str()).ok_or_else(|| {
+                JsNativeError::typ().with_message(format!(
+                    "placement should be \"before\" or \"after\": {}",
+                    placement
+                ))
+            })?
+        };
+
+        let delay = options.get(js_string!("delay"), context)?;
+        let delay = if delay.is_undefined() {
+            Duration::ZERO
+        } else {
+            Duration::from_millis(delay.to_u32(context)? as u64)
+        };
+
+        Ok((placement, delay))
+    }
+
     pub fn load_app_config_json(
         _this: &JsValue,
         _args: &[JsValue],
@@ -70,11 +70,37 @@ pub struct TimedLogMessage {
 
 lazy_static! {
     static ref CONSOLE_LOG_BUFFER: RwLock<VecDeque<TimedLogMessage>> = RwLock::new(VecDeque::new());
+    // The output of the code evaluated from the REPL.
+    static ref CONSOLE_CAPTURE: RwLock<Option<Vec<TimedLogMessage>>> = RwLock::new(None);
+}
+
+/// Start collecting the console output, in addition to the log buffer.
+pub(crate) fn start_capture() {
+    match CONSOLE_CAPTURE.write() {
+        Ok(mut capture) => *capture = Some(Vec::new()),
+        Err(err) => log::error!("Cannot get lock for console capture: {:?}", err),
+    }
+}
+
+/// Stop collecting the console output, and returns the collected messages.
+pub(crate) fn finish_capture() -> Vec<TimedLogMessage> {
+    match CONSOLE_CAPTURE.write() {
+        Ok(mut capture) => capture.take().unwrap_or_default(),
+        Err(err) => {
+            log::error!("Cannot get lock for console capture: {:?}", err);
+            vec![]
+        }
+    }
 }
 
 pub fn get_console_logs() -> Vec<TimedLogMessage> {
-    let logs = CONSOLE_LOG_BUFFER.read().expect("Get console log buffer");
-    logs.iter().cloned().collect()
+    match CONSOLE_LOG_BUFFER.read() {
+        Ok(logs) => logs.iter().cloned().collect(),
+        Err(err) => {
+            log::error!("Cannot get lock for console log buffer: {:?}", err);
+            vec![]
+        }
+    }
 }
 
 /// Helper function for logging messages.
@@ -93,15 +119,32 @@ fn logger(msg: LogMessage, console_state: &Console) {
         }
     }
 
-    let mut buffer = CONSOLE_LOG_BUFFER.write().expect("Get log buffer");
-    buffer.push_back(TimedLogMessage {
+    let timed_message = TimedLogMessage {
         time_seconds: match SystemTime::now().duration_since(UNIX_EPOCH) {
             Ok(n) => n.as_secs(),
             Err(_) => 0,
         },
         level: msg.level().to_string(),
         message: msg.message(),
-    });
+    };
+
+    match CONSOLE_CAPTURE.write() {
+        Ok(mut capture) => {
+            if let Some(capture) = capture.as_mut() {
+                capture.push(timed_message.clone());
+            }
+        }
+        Err(err) => log::error!("Cannot get lock for console capture: {:?}", err),
+    }
+
+    let mut buffer = match CONSOLE_LOG_BUFFER.write() {
+        Ok(buffer) => buffer,
+        Err(err) => {
+            log::error!("Cannot get lock for console log buffer: {:?}", err);
+            return;
+        }
+    };
+    buffer.push_back(timed_message);
 }
 
 /// This represents the `console` formatter.
@@ -561,9 +604,10 @@ impl Console {
     /// Returns current system time in ms.
     fn system_time_in_ms() -> u128 {
         let now = SystemTime::now();
-        now.duration_since(SystemTime::UNIX_EPOCH)
-            .expect("negative duration")
-            .as_millis()
+        match now.duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(n) => n.as_millis(),
+            Err(_) => 0,
+        }
     }
 
     /// `console.time(label)`
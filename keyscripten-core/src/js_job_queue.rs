@@ -0,0 +1,63 @@
+use crate::js_builtin::mark_aborted;
+use boa_engine::context::HostHooks;
+use boa_engine::job::{FutureJob, JobCallback, JobQueue, NativeJob};
+use boa_engine::{Context, JsNativeError, JsResult, JsValue};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+
+/// The promise jobs. `JS` takes them one by one, and runs each as the plugin owning its realm.
+/// Unlike `SimpleJobQueue`, the aborted job doesn't drop the other plugins' jobs.
+#[derive(Default)]
+pub(crate) struct PluginJobQueue(RefCell<VecDeque<NativeJob>>);
+
+impl PluginJobQueue {
+    pub(crate) fn pop(&self) -> Option<NativeJob> {
+        self.0.borrow_mut().pop_front()
+    }
+}
+
+impl JobQueue for PluginJobQueue {
+    fn enqueue_promise_job(&self, job: NativeJob, _context: &mut Context) {
+        self.0.borrow_mut().push_back(job);
+    }
+
+    // Called by `Context::run_jobs`, e.g. while the modules are evaluated.
+    fn run_jobs(&self, context: &mut Context) {
+        while let Some(job) = self.pop() {
+            if let Err(err) = job.call(context) {
+                log::error!("Cannot run the promise job: {:?}", err);
+            }
+        }
+    }
+
+    // No native function returns a future.
+    fn enqueue_future_job(&self, _future: FutureJob, _context: &mut Context) {
+        log::error!("Future jobs are not supported");
+    }
+}
+
+/// Boa panics if the promise reaction fails with the runtime limit error, since it can't reject
+/// the promise with it. The error is turned into the catchable one, and the abort is recorded.
+pub(crate) struct PluginHostHooks;
+
+impl HostHooks for PluginHostHooks {
+    fn call_job_callback(
+        &self,
+        job: JobCallback,
+        this: &JsValue,
+        args: &[JsValue],
+        context: &mut Context,
+    ) -> JsResult<JsValue> {
+        job.callback()
+            .call(this, args, context)
+            .map_err(|err| match err.as_native() {
+                Some(native) if native.is_runtime_limit() => {
+                    mark_aborted();
+                    JsNativeError::error()
+                        .with_message(native.message().to_string())
+                        .into()
+                }
+                _ => err,
+            })
+    }
+}
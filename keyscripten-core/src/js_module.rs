@@ -0,0 +1,216 @@
+use anyhow::anyhow;
+use boa_engine::module::{ModuleLoader, Referrer};
+use boa_engine::{Context, JsNativeError, JsResult, JsString, Module, Source};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+// The modules provided by KeyScripten. They re-export the builtin functions.
+const BUILTIN_MODULES: [(&str, &str); 2] = [
+    ("keyscripten:keys", include_str!("../js/modules/keys.js")),
+    ("keyscripten:send", include_str!("../js/modules/send.js")),
+];
+
+// `import ... from "lib/foo.js"` reads `foo.js` in the shared library folder.
+const LIB_PREFIX: &str = "lib/";
+
+/// Loads the modules imported by the plugins.
+///
+/// The plugins can import the builtin modules, the modules in the shared library folder,
+/// and the modules in their own plugin package. Nothing else.
+pub(crate) struct PluginModuleLoader {
+    lib_dir: Option<PathBuf>,
+    // The plugin of each module, so the imports are resolved for the importing plugin.
+    owners: RefCell<HashMap<Module, Rc<ModuleOwner>>>,
+    // The modules are cached per plugin, so the plugins don't share the module state.
+    modules: RefCell<HashMap<(String, PathBuf), Module>>,
+}
+
+// The plugin which loaded the module.
+struct ModuleOwner {
+    filename: String,
+    package_dir: Option<PathBuf>,
+}
+
+impl PluginModuleLoader {
+    pub(crate) fn new(lib_dir: Option<PathBuf>) -> Self {
+        PluginModuleLoader {
+            lib_dir,
+            owners: RefCell::new(HashMap::new()),
+            modules: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Called with the entry module of the plugin, before it's evaluated.
+    pub(crate) fn register_plugin(
+        &self,
+        module: &Module,
+        filename: &str,
+        package_dir: Option<PathBuf>,
+    ) {
+        let owner = ModuleOwner {
+            filename: filename.to_string(),
+            package_dir,
+        };
+        self.owners
+            .borrow_mut()
+            .insert(module.clone(), Rc::new(owner));
+    }
+
+    /// Drop the modules loaded by the unloaded plugin.
+    pub(crate) fn forget_plugin(&self, filename: &str) {
+        self.owners
+            .borrow_mut()
+            .retain(|_, owner| owner.filename != filename);
+        self.modules
+            .borrow_mut()
+            .retain(|(plugin, _), _| plugin != filename);
+    }
+
+    fn load(
+        &self,
+        referrer: &Referrer,
+        specifier: &str,
+        context: &mut Context,
+    ) -> anyhow::Result<Module> {
+        let owner = match referrer {
+            Referrer::Module(module) => self.owners.borrow().get(module).cloned(),
+            _ => None,
+        }
+        .ok_or_else(|| anyhow!("Modules can be imported only from the plugins"))?;
+
+        if let Some((_, src)) = BUILTIN_MODULES.iter().find(|(name, _)| *name == specifier) {
+            return self.parse_cached(owner, PathBuf::from(specifier), context, |_| {
+                Ok(src.to_string())
+            });
+        }
+
+        let mut roots = Vec::new();
+        if let Some(lib_dir) = &self.lib_dir {
+            roots.push(lib_dir.as_path());
+        }
+        if let Some(package_dir) = &owner.package_dir {
+            roots.push(package_dir.as_path());
+        }
+        let path = resolve_specifier(specifier, referrer.path(), self.lib_dir.as_deref(), &roots)?;
+        self.parse_cached(owner.clone(), path, context, |path| {
+            fs::read_to_string(path).map_err(|err| anyhow!("Cannot read {:?}: {:?}", path, err))
+        })
+    }
+
+    fn parse_cached(
+        &self,
+        owner: Rc<ModuleOwner>,
+        path: PathBuf,
+        context: &mut Context,
+        read: impl FnOnce(&Path) -> anyhow::Result<String>,
+    ) -> anyhow::Result<Module> {
+        let key = (owner.filename.clone(), path);
+        if let Some(module) = self.modules.borrow().get(&key) {
+            return Ok(module.clone());
+        }
+
+        let src = read(&key.1)?;
+        let module = Module::parse(
+            Source::from_bytes(src.as_bytes()).with_path(&key.1),
+            None,
+            context,
+        )
+        .map_err(|err| anyhow!("Cannot parse module {:?}: {}", key.1, err))?;
+        self.modules.borrow_mut().insert(key, module.clone());
+        self.owners.borrow_mut().insert(module.clone(), owner);
+        Ok(module)
+    }
+}
+
+impl ModuleLoader for PluginModuleLoader {
+    fn load_imported_module(
+        &self,
+        referrer: Referrer,
+        specifier: JsString,
+        finish_load: Box<dyn FnOnce(JsResult<Module>, &mut Context)>,
+        context: &mut Context,
+    ) {
+        let specifier = specifier.to_std_string_escaped();
+        let result = self.load(&referrer, &specifier, context).map_err(|err| {
+            JsNativeError::typ()
+                .with_message(format!("Cannot import {:?}: {}", specifier, err))
+                .into()
+        });
+        finish_load(result, context);
+    }
+}
+
+// Resolve the module specifier to the file path. The file must be under one of `roots`.
+fn resolve_specifier(
+    specifier: &str,
+    referrer: Option<&Path>,
+    lib_dir: Option<&Path>,
+    roots: &[&Path],
+) -> anyhow::Result<PathBuf> {
+    let path = if let Some(rest) = specifier.strip_prefix(LIB_PREFIX) {
+        lib_dir
+            .ok_or_else(|| anyhow!("There's no library folder"))?
+            .join(rest)
+    } else if specifier.starts_with("./") || specifier.starts_with("../") {
+        referrer
+            .and_then(|referrer| referrer.parent())
+            .ok_or_else(|| anyhow!("Relative import needs the importing module's path"))?
+            .join(specifier)
+    } else {
+        return Err(anyhow!("Unknown module"));
+    };
+
+    let path = path
+        .canonicalize()
+        .map_err(|err| anyhow!("Cannot find {:?}: {:?}", path, err))?;
+    for root in roots {
+        if let Ok(root) = root.canonicalize() {
+            if path.starts_with(&root) {
+                return Ok(path);
+            }
+        }
+    }
+    Err(anyhow!(
+        "{:?} is out of the library folder and the plugin package",
+        path
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_resolve_specifier() -> anyhow::Result<()> {
+        let temp_dir = TempDir::with_prefix("modules")?;
+        let lib_dir = temp_dir.path().join("lib");
+        let package_dir = temp_dir.path().join("plugins").join("package");
+        fs::create_dir_all(&lib_dir)?;
+        fs::create_dir_all(&package_dir)?;
+        fs::write(lib_dir.join("flags.js"), "")?;
+        fs::write(package_dir.join("index.js"), "")?;
+        fs::write(package_dir.join("util.js"), "")?;
+        fs::write(temp_dir.path().join("plugins").join("other.js"), "")?;
+        let roots = [lib_dir.as_path(), package_dir.as_path()];
+        let referrer = package_dir.join("index.js");
+
+        let path = resolve_specifier("lib/flags.js", None, Some(&lib_dir), &roots)?;
+        assert_eq!(path, lib_dir.join("flags.js").canonicalize()?);
+        let path = resolve_specifier("./util.js", Some(&referrer), Some(&lib_dir), &roots)?;
+        assert_eq!(path, package_dir.join("util.js").canonicalize()?);
+
+        // out of the roots
+        assert!(resolve_specifier("../other.js", Some(&referrer), Some(&lib_dir), &roots).is_err());
+        assert!(
+            resolve_specifier("lib/../plugins/other.js", None, Some(&lib_dir), &roots).is_err()
+        );
+        // bare specifiers and absolute paths
+        assert!(resolve_specifier("fs", None, Some(&lib_dir), &roots).is_err());
+        assert!(resolve_specifier("/etc/passwd", None, Some(&lib_dir), &roots).is_err());
+        Ok(())
+    }
+}
@@ -1,10 +1,92 @@
+use crate::js::{ConfigSchemaList, EvalResult};
+use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
+use std::sync::mpsc::Sender;
+use std::sync::Mutex;
+
+lazy_static! {
+    static ref RESULT_LISTENER: Mutex<Option<Sender<JsOperationResult>>> = Mutex::new(None);
+}
 
 #[derive(Debug, Deserialize, Serialize)]
 pub enum JsOperation {
     ReloadConfig,
     ReloadPlugins,
-    UnloadPlugin { plugin_id: String },
+    UnloadPlugin {
+        plugin_id: String,
+    },
+    SetPluginEnabled {
+        plugin_id: String,
+        enabled: bool,
+    },
+    // The queries from the Tauri commands. The worker answers with the state of the loaded plugins.
+    #[serde(skip)]
+    GetConfigSchema {
+        reply: Sender<anyhow::Result<ConfigSchemaList>>,
+    },
+    #[serde(skip)]
+    GetFilenameByPluginId {
+        plugin_id: String,
+        reply: Sender<Option<String>>,
+    },
+    // Evaluate the code from the REPL. `plugin_id` selects the plugin's scope.
+    #[serde(skip)]
+    Eval {
+        code: String,
+        plugin_id: Option<String>,
+        reply: Sender<EvalResult>,
+    },
+}
+
+impl JsOperation {
+    pub fn name(&self) -> &'static str {
+        match self {
+            JsOperation::ReloadConfig => "ReloadConfig",
+            JsOperation::ReloadPlugins => "ReloadPlugins",
+            JsOperation::UnloadPlugin { .. } => "UnloadPlugin",
+            JsOperation::SetPluginEnabled { .. } => "SetPluginEnabled",
+            JsOperation::GetConfigSchema { .. } => "GetConfigSchema",
+            JsOperation::GetFilenameByPluginId { .. } => "GetFilenameByPluginId",
+            JsOperation::Eval { .. } => "Eval",
+        }
+    }
+}
+
+/// Result of the operation, reported to the UI.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct JsOperationResult {
+    pub operation: String,
+    pub error: Option<String>,
+}
+
+impl JsOperationResult {
+    pub fn new(operation: &str, result: anyhow::Result<()>) -> Self {
+        JsOperationResult {
+            operation: operation.to_string(),
+            error: result.err().map(|err| format!("{:#}", err)),
+        }
+    }
+}
+
+/// Register the channel notified with the result of each operation.
+pub fn set_result_listener(tx: Sender<JsOperationResult>) {
+    match RESULT_LISTENER.lock() {
+        Ok(mut listener) => *listener = Some(tx),
+        Err(err) => log::error!("Cannot get lock for js-operation listener: {:?}", err),
+    }
+}
+
+pub(crate) fn report_js_operation_result(result: JsOperationResult) {
+    match RESULT_LISTENER.lock() {
+        Ok(listener) => {
+            if let Some(tx) = listener.as_ref() {
+                if let Err(err) = tx.send(result) {
+                    log::error!("Cannot report js-operation result: {:?}", err);
+                }
+            }
+        }
+        Err(err) => log::error!("Cannot get lock for js-operation listener: {:?}", err),
+    }
 }
 
 #[cfg(test)]
@@ -24,10 +106,28 @@ mod tests {
         let json = r#"{ "UnloadPlugin": { "plugin_id": "example-plugin" } }"#;
         let op: JsOperation = serde_json::from_str(json).unwrap();
         match op {
-            JsOperation::UnloadPlugin {
-                plugin_id,
-            } => assert_eq!(plugin_id, "example-plugin"),
+            JsOperation::UnloadPlugin { plugin_id } => assert_eq!(plugin_id, "example-plugin"),
             _ => panic!("Expected UnloadPlugin"),
         }
+
+        let json = r#"{ "SetPluginEnabled": { "plugin_id": "example-plugin", "enabled": false } }"#;
+        let op: JsOperation = serde_json::from_str(json).unwrap();
+        match op {
+            JsOperation::SetPluginEnabled { plugin_id, enabled } => {
+                assert_eq!(plugin_id, "example-plugin");
+                assert!(!enabled);
+            }
+            _ => panic!("Expected SetPluginEnabled"),
+        }
+    }
+
+    #[test]
+    fn test_js_operation_result() {
+        let result = JsOperationResult::new(JsOperation::ReloadConfig.name(), Ok(()));
+        assert_eq!(result.operation, "ReloadConfig");
+        assert_eq!(result.error, None);
+
+        let result = JsOperationResult::new("ReloadPlugins", Err(anyhow::anyhow!("broken")));
+        assert_eq!(result.error, Some("broken".to_string()));
     }
 }
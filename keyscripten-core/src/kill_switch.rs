@@ -0,0 +1,122 @@
+use crate::event::Event;
+use crate::hotkey::HotKey;
+use apple_sys::CoreGraphics::CGKeyCode;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+const KEYCODE_ESCAPE: i64 = 53;
+// Pressing Escape this many times within ESCAPE_WINDOW engages the kill switch.
+const ESCAPE_PRESSES: usize = 5;
+const ESCAPE_WINDOW: Duration = Duration::from_millis(1500);
+
+/// Detects the emergency escape, without any help of the plugins.
+pub struct KillSwitch {
+    escapes: VecDeque<Instant>,
+    panic_chord: Option<HotKey>,
+}
+
+impl KillSwitch {
+    /// `panic_chord` is the hotkey in the `C-S-M-escape` format.
+    pub fn new(panic_chord: Option<&str>) -> anyhow::Result<KillSwitch> {
+        let panic_chord = match panic_chord {
+            Some(s) => Some(HotKey::from_str(s)?),
+            None => None,
+        };
+        Ok(KillSwitch {
+            escapes: VecDeque::new(),
+            panic_chord,
+        })
+    }
+
+    /// Returns true if the event triggers the kill switch.
+    pub fn observe(&mut self, event: &Event, now: Instant) -> bool {
+        if event.event_type != "keyDown" || event.autorepeat {
+            return false;
+        }
+
+        if let Some(panic_chord) = &self.panic_chord {
+            if panic_chord.matches(event.flags, event.keycode as CGKeyCode) {
+                return true;
+            }
+        }
+
+        if event.keycode != KEYCODE_ESCAPE {
+            self.escapes.clear();
+            return false;
+        }
+        self.escapes.push_back(now);
+        while let Some(at) = self.escapes.front() {
+            if now.duration_since(*at) > ESCAPE_WINDOW {
+                self.escapes.pop_front();
+            } else {
+                break;
+            }
+        }
+        if self.escapes.len() >= ESCAPE_PRESSES {
+            self.escapes.clear();
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use apple_sys::CoreGraphics::{
+        CGEventFlags_kCGEventFlagMaskCommand, CGEventFlags_kCGEventFlagMaskControl,
+    };
+
+    fn key_down(keycode: i64, flags: u64) -> Event {
+        Event {
+            event_type: "keyDown".to_string(),
+            keycode,
+            flags,
+            autorepeat: false,
+            timestamp: 0,
+        }
+    }
+
+    #[test]
+    fn test_escape_presses() -> anyhow::Result<()> {
+        let mut kill_switch = KillSwitch::new(None)?;
+        let now = Instant::now();
+        for _ in 0..ESCAPE_PRESSES - 1 {
+            assert!(!kill_switch.observe(&key_down(KEYCODE_ESCAPE, 0), now));
+        }
+        assert!(kill_switch.observe(&key_down(KEYCODE_ESCAPE, 0), now));
+        // starts over.
+        assert!(!kill_switch.observe(&key_down(KEYCODE_ESCAPE, 0), now));
+        Ok(())
+    }
+
+    #[test]
+    fn test_slow_or_interrupted_escape_presses() -> anyhow::Result<()> {
+        let mut kill_switch = KillSwitch::new(None)?;
+        let now = Instant::now();
+        for i in 0..ESCAPE_PRESSES as u32 {
+            let at = now + ESCAPE_WINDOW * i / 2;
+            assert!(!kill_switch.observe(&key_down(KEYCODE_ESCAPE, 0), at));
+        }
+
+        let mut kill_switch = KillSwitch::new(None)?;
+        for _ in 0..ESCAPE_PRESSES - 1 {
+            kill_switch.observe(&key_down(KEYCODE_ESCAPE, 0), now);
+        }
+        assert!(!kill_switch.observe(&key_down(17, 0), now));
+        assert!(!kill_switch.observe(&key_down(KEYCODE_ESCAPE, 0), now));
+        Ok(())
+    }
+
+    #[test]
+    fn test_panic_chord() -> anyhow::Result<()> {
+        let mut kill_switch = KillSwitch::new(Some("C-M-escape"))?;
+        let now = Instant::now();
+        let flags = CGEventFlags_kCGEventFlagMaskControl | CGEventFlags_kCGEventFlagMaskCommand;
+        assert!(!kill_switch.observe(&key_down(17, flags), now));
+        assert!(kill_switch.observe(&key_down(KEYCODE_ESCAPE, flags), now));
+
+        assert!(KillSwitch::new(Some("C-unknown")).is_err());
+        Ok(())
+    }
+}
@@ -1,15 +1,31 @@
 pub mod app_config;
+pub mod config_migration;
+pub mod config_schema;
+mod consumed_keys;
+pub mod crash_report;
 pub mod event;
+pub mod file_security;
 pub mod grab;
 mod hotkey;
 pub mod js;
 mod js_builtin;
 pub mod js_console;
 mod js_hotkey;
+mod js_job_queue;
 mod js_keycode;
+mod js_module;
 pub mod js_operation;
 mod keycode;
+pub mod kill_switch;
+mod modifier_state;
+mod output;
+pub mod passthrough;
 pub mod plugin;
+pub mod plugin_manifest;
+mod pressed_keys;
+pub mod quarantine;
 mod send;
+pub mod session;
+pub mod worker;
 
 const APP_NAME: &str = "keyscripten";
@@ -0,0 +1,124 @@
+use crate::output::{enqueue, last_queued_flags, Placement, SyntheticEvent};
+use apple_sys::CoreGraphics::{
+    CGEventFlags, CGEventFlags_kCGEventFlagMaskAlphaShift, CGEventFlags_kCGEventFlagMaskAlternate,
+    CGEventFlags_kCGEventFlagMaskCommand, CGEventFlags_kCGEventFlagMaskControl,
+    CGEventFlags_kCGEventFlagMaskNonCoalesced, CGEventFlags_kCGEventFlagMaskSecondaryFn,
+    CGEventFlags_kCGEventFlagMaskShift,
+};
+use lazy_static::lazy_static;
+use std::sync::RwLock;
+use std::time::Duration;
+
+lazy_static! {
+    static ref MODIFIER_STATE: RwLock<ModifierState> = RwLock::new(ModifierState::default());
+}
+
+const MODIFIER_MASK: CGEventFlags = CGEventFlags_kCGEventFlagMaskAlphaShift
+    | CGEventFlags_kCGEventFlagMaskShift
+    | CGEventFlags_kCGEventFlagMaskControl
+    | CGEventFlags_kCGEventFlagMaskAlternate
+    | CGEventFlags_kCGEventFlagMaskCommand
+    | CGEventFlags_kCGEventFlagMaskSecondaryFn;
+
+/// Modifier state, as `CGEventFlags`.
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct ModifierState {
+    // The state of the keyboard, taken from the user's input.
+    pub physical: CGEventFlags,
+    // The state we have posted by the flagsChanged events.
+    pub synthetic: CGEventFlags,
+}
+
+impl ModifierState {
+    fn observe_physical(&mut self, flags: CGEventFlags) {
+        self.physical = flags;
+        // The OS follows the keyboard again.
+        self.synthetic = flags;
+    }
+
+    fn observe_synthetic(&mut self, flags: CGEventFlags) {
+        self.synthetic = flags;
+    }
+
+    pub fn is_diverged(&self) -> bool {
+        self.physical & MODIFIER_MASK != self.synthetic & MODIFIER_MASK
+    }
+}
+
+pub fn get_modifier_state() -> ModifierState {
+    match MODIFIER_STATE.read() {
+        Ok(state) => *state,
+        Err(err) => {
+            log::error!("Cannot get lock for modifier state: {:?}", err);
+            ModifierState::default()
+        }
+    }
+}
+
+/// Called when the user's input was received.
+pub fn observe_physical_flags(flags: CGEventFlags) {
+    match MODIFIER_STATE.write() {
+        Ok(mut state) => state.observe_physical(flags),
+        Err(err) => log::error!("Cannot get lock for modifier state: {:?}", err),
+    }
+}
+
+/// Called when this app posted the flagsChanged event.
+pub fn observe_synthetic_flags(flags: CGEventFlags) {
+    match MODIFIER_STATE.write() {
+        Ok(mut state) => state.observe_synthetic(flags),
+        Err(err) => log::error!("Cannot get lock for modifier state: {:?}", err),
+    }
+}
+
+/// Release all modifier keys, from the application's point of view.
+pub fn release_all_modifiers(placement: Placement) {
+    enqueue(
+        SyntheticEvent::FlagsChanged {
+            flags: CGEventFlags_kCGEventFlagMaskNonCoalesced,
+        },
+        placement,
+        Duration::ZERO,
+    );
+}
+
+/// Restore the modifier state to the physical state, if the plugin changed it.
+pub fn resync_modifiers() {
+    let mut state = get_modifier_state();
+    // The flagsChanged events sent by the plugin may not be posted yet.
+    if let Some(flags) = last_queued_flags() {
+        state.observe_synthetic(flags);
+    }
+    if !state.is_diverged() {
+        return;
+    }
+    log::debug!("Resync modifiers: {:?}", state);
+    enqueue(
+        SyntheticEvent::FlagsChanged {
+            flags: state.physical | CGEventFlags_kCGEventFlagMaskNonCoalesced,
+        },
+        Placement::AfterOriginal,
+        Duration::ZERO,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_modifier_state() {
+        let mut state = ModifierState::default();
+        state.observe_physical(CGEventFlags_kCGEventFlagMaskControl);
+        assert!(!state.is_diverged());
+
+        // macro released the control key.
+        state.observe_synthetic(CGEventFlags_kCGEventFlagMaskNonCoalesced);
+        assert!(state.is_diverged());
+
+        // user released the control key.
+        state.observe_physical(CGEventFlags_kCGEventFlagMaskNonCoalesced);
+        assert!(!state.is_diverged());
+        assert_eq!(state.synthetic, CGEventFlags_kCGEventFlagMaskNonCoalesced);
+    }
+}
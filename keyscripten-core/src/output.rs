@@ -0,0 +1,383 @@
+use crate::modifier_state::observe_synthetic_flags;
+use crate::send::{
+    copy_event, post_event, release_event, send_flags_changed_event, send_keyboard_event,
+};
+use apple_sys::CoreGraphics::{CGEventFlags, CGEventRef, CGKeyCode};
+use lazy_static::lazy_static;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    static ref OUTPUT_QUEUE: Mutex<OutputQueue> = Mutex::new(OutputQueue::default());
+}
+
+/// Where the synthesized event is placed, relative to the event that is being handled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Placement {
+    BeforeOriginal,
+    AfterOriginal,
+}
+
+impl Placement {
+    pub fn from_str(s: &str) -> Option<Placement> {
+        match s {
+            "before" => Some(Placement::BeforeOriginal),
+            "after" => Some(Placement::AfterOriginal),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyntheticEvent {
+    Keyboard {
+        keycode: CGKeyCode,
+        flags: CGEventFlags,
+        keydown: bool,
+    },
+    FlagsChanged {
+        flags: CGEventFlags,
+    },
+    // Copy of the original event. It's re-posted when other events must be posted before it.
+    Original(OriginalEvent),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OriginalEvent(CGEventRef);
+
+// The copy is owned by the queue. It's posted from the event tap thread or the JS worker thread.
+// It leaves the queue only by `post_all`, which releases it.
+unsafe impl Send for OriginalEvent {}
+
+#[derive(Debug, Clone, PartialEq)]
+struct QueuedEvent {
+    event: SyntheticEvent,
+    delay: Duration,
+}
+
+/// Events enqueued while one event was handled, taken out of the queue.
+/// They are posted around the original event by `flush_pending`.
+#[derive(Default, Debug)]
+pub struct Pending {
+    before: Vec<QueuedEvent>,
+    after: Vec<QueuedEvent>,
+}
+
+#[derive(Default)]
+struct OutputQueue {
+    before: Vec<QueuedEvent>,
+    after: Vec<QueuedEvent>,
+    // Events waiting for their delay, in posting order.
+    scheduled: VecDeque<(Instant, SyntheticEvent)>,
+}
+
+impl OutputQueue {
+    fn push(&mut self, event: SyntheticEvent, placement: Placement, delay: Duration) {
+        let queued = QueuedEvent { event, delay };
+        match placement {
+            Placement::BeforeOriginal => self.before.push(queued),
+            Placement::AfterOriginal => self.after.push(queued),
+        }
+    }
+
+    fn take_pending(&mut self) -> Pending {
+        Pending {
+            before: std::mem::take(&mut self.before),
+            after: std::mem::take(&mut self.after),
+        }
+    }
+
+    // The original event can't be passed as is, if other events must be posted before it.
+    fn must_repost_original(&self, pending: &Pending) -> bool {
+        !pending.before.is_empty() || !self.scheduled.is_empty()
+    }
+
+    // Builds the posting order for the handled event.
+    // `original` is the copy of the original event, if it must be re-posted.
+    fn schedule(&mut self, pending: Pending, original: Option<OriginalEvent>, now: Instant) {
+        let mut sequence = pending.before;
+        if let Some(original) = original {
+            sequence.push(QueuedEvent {
+                event: SyntheticEvent::Original(original),
+                delay: Duration::ZERO,
+            });
+        }
+        sequence.extend(pending.after);
+
+        let mut at = self
+            .scheduled
+            .back()
+            .map(|(at, _)| (*at).max(now))
+            .unwrap_or(now);
+        for queued in sequence {
+            at += queued.delay;
+            self.scheduled.push_back((at, queued.event));
+        }
+    }
+
+    // Drops the synthesized events. Returns the original events, which must not be lost.
+    fn clear(&mut self) -> Vec<SyntheticEvent> {
+        self.before.clear();
+        self.after.clear();
+        self.scheduled
+            .drain(..)
+            .map(|(_, event)| event)
+            .filter(|event| matches!(event, SyntheticEvent::Original(_)))
+            .collect()
+    }
+
+    // The flags of the flagsChanged event, which will be posted last among the queued ones.
+    fn last_flags(&self) -> Option<CGEventFlags> {
+        self.scheduled
+            .iter()
+            .map(|(_, event)| event)
+            .chain(self.before.iter().map(|queued| &queued.event))
+            .chain(self.after.iter().map(|queued| &queued.event))
+            .filter_map(|event| match event {
+                SyntheticEvent::FlagsChanged { flags } => Some(*flags),
+                _ => None,
+            })
+            .last()
+    }
+
+    fn take_due(&mut self, now: Instant) -> Vec<SyntheticEvent> {
+        let mut result = Vec::new();
+        while let Some((at, _)) = self.scheduled.front() {
+            if *at > now {
+                break;
+            }
+            if let Some((_, event)) = self.scheduled.pop_front() {
+                result.push(event);
+            }
+        }
+        result
+    }
+}
+
+/// Queue the synthesized event. It's posted by `flush`, after the decision for the original event.
+pub fn enqueue(event: SyntheticEvent, placement: Placement, delay: Duration) {
+    match OUTPUT_QUEUE.lock() {
+        Ok(mut queue) => queue.push(event, placement, delay),
+        Err(err) => log::error!("Cannot get lock for output queue: {:?}", err),
+    }
+}
+
+/// Take the events enqueued by the handler of the current event.
+pub fn take_pending() -> Pending {
+    match OUTPUT_QUEUE.lock() {
+        Ok(mut queue) => queue.take_pending(),
+        Err(err) => {
+            log::error!("Cannot get lock for output queue: {:?}", err);
+            Pending::default()
+        }
+    }
+}
+
+/// The flags of the queued flagsChanged event, which is not posted yet.
+pub fn last_queued_flags() -> Option<CGEventFlags> {
+    match OUTPUT_QUEUE.lock() {
+        Ok(queue) => queue.last_flags(),
+        Err(err) => {
+            log::error!("Cannot get lock for output queue: {:?}", err);
+            None
+        }
+    }
+}
+
+/// Drop the events sent by the plugins. The user's own events are posted right now.
+pub fn clear() {
+    let events = match OUTPUT_QUEUE.lock() {
+        Ok(mut queue) => queue.clear(),
+        Err(err) => {
+            log::error!("Cannot get lock for output queue: {:?}", err);
+            return;
+        }
+    };
+    post_all(events);
+}
+
+/// Post the queued events, which were not taken by `take_pending`.
+pub fn flush(original: Option<CGEventRef>) -> bool {
+    flush_pending(take_pending(), original)
+}
+
+/// Post the pending events and the due scheduled events.
+///
+/// `original` is the event handled by the event tap, if it's passed to the application.
+/// Returns true if the caller must drop the original event, since the copy of it will be posted
+/// after the events placed before it.
+pub fn flush_pending(pending: Pending, original: Option<CGEventRef>) -> bool {
+    let (drop_original, events) = match OUTPUT_QUEUE.lock() {
+        Ok(mut queue) => {
+            // The original event is only valid while the tap callback runs. Copy it.
+            let original = match original {
+                Some(cg_event) if queue.must_repost_original(&pending) => {
+                    match copy_event(cg_event) {
+                        Ok(copy) => Some(OriginalEvent(copy)),
+                        Err(err) => {
+                            log::error!("Cannot copy the original event: {:?}", err);
+                            None
+                        }
+                    }
+                }
+                _ => None,
+            };
+            let drop_original = original.is_some();
+
+            let now = Instant::now();
+            queue.schedule(pending, original, now);
+            (drop_original, queue.take_due(now))
+        }
+        Err(err) => {
+            log::error!("Cannot get lock for output queue: {:?}", err);
+            return false;
+        }
+    };
+
+    post_all(events);
+    drop_original
+}
+
+fn post_all(events: Vec<SyntheticEvent>) {
+    for event in events {
+        let result = match event {
+            SyntheticEvent::Keyboard {
+                keycode,
+                flags,
+                keydown,
+            } => send_keyboard_event(keycode, flags, keydown),
+            SyntheticEvent::FlagsChanged { flags } => {
+                observe_synthetic_flags(flags);
+                send_flags_changed_event(flags)
+            }
+            SyntheticEvent::Original(OriginalEvent(cg_event)) => {
+                let result = post_event(cg_event);
+                release_event(cg_event);
+                result
+            }
+        };
+        if let Err(err) = result {
+            log::error!("Cannot post the queued event({:?}): {:?}", event, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(keycode: CGKeyCode) -> SyntheticEvent {
+        SyntheticEvent::Keyboard {
+            keycode,
+            flags: 0,
+            keydown: true,
+        }
+    }
+
+    #[test]
+    fn test_schedule_order() {
+        let mut queue = OutputQueue::default();
+        queue.push(key(2), Placement::AfterOriginal, Duration::ZERO);
+        queue.push(key(1), Placement::BeforeOriginal, Duration::ZERO);
+
+        let original = OriginalEvent(std::ptr::null_mut());
+        let now = Instant::now();
+        let pending = queue.take_pending();
+        assert!(queue.must_repost_original(&pending));
+        queue.schedule(pending, Some(original), now);
+        assert_eq!(
+            queue.take_due(now),
+            vec![key(1), SyntheticEvent::Original(original), key(2)]
+        );
+    }
+
+    #[test]
+    fn test_schedule_after_only() {
+        let mut queue = OutputQueue::default();
+        queue.push(key(2), Placement::AfterOriginal, Duration::ZERO);
+
+        let now = Instant::now();
+        // the original event is passed as is.
+        let pending = queue.take_pending();
+        assert!(!queue.must_repost_original(&pending));
+        queue.schedule(pending, None, now);
+        assert_eq!(queue.take_due(now), vec![key(2)]);
+    }
+
+    #[test]
+    fn test_schedule_delay() {
+        let mut queue = OutputQueue::default();
+        queue.push(key(1), Placement::AfterOriginal, Duration::ZERO);
+        queue.push(key(2), Placement::AfterOriginal, Duration::from_millis(50));
+        queue.push(key(3), Placement::AfterOriginal, Duration::ZERO);
+
+        let now = Instant::now();
+        let pending = queue.take_pending();
+        queue.schedule(pending, None, now);
+        assert_eq!(queue.take_due(now), vec![key(1)]);
+        // key(3) waits for the delayed key(2).
+        assert_eq!(
+            queue.take_due(now + Duration::from_millis(50)),
+            vec![key(2), key(3)]
+        );
+    }
+
+    #[test]
+    fn test_original_waits_for_scheduled_events() {
+        let mut queue = OutputQueue::default();
+        queue.push(key(1), Placement::AfterOriginal, Duration::from_millis(50));
+
+        let now = Instant::now();
+        let pending = queue.take_pending();
+        queue.schedule(pending, None, now);
+        assert!(queue.take_due(now).is_empty());
+
+        // the next original event must be posted after the pending events.
+        let pending = queue.take_pending();
+        assert!(queue.must_repost_original(&pending));
+        let original = OriginalEvent(std::ptr::null_mut());
+        queue.schedule(pending, Some(original), now);
+        assert!(queue.take_due(now).is_empty());
+        assert_eq!(
+            queue.take_due(now + Duration::from_millis(50)),
+            vec![key(1), SyntheticEvent::Original(original)]
+        );
+    }
+
+    #[test]
+    fn test_last_flags() {
+        let mut queue = OutputQueue::default();
+        assert_eq!(queue.last_flags(), None);
+
+        let flags = |flags| SyntheticEvent::FlagsChanged { flags };
+        queue.push(
+            flags(1),
+            Placement::AfterOriginal,
+            Duration::from_millis(50),
+        );
+        let pending = queue.take_pending();
+        queue.schedule(pending, None, Instant::now());
+        assert_eq!(queue.last_flags(), Some(1));
+
+        // the pending events are posted after the scheduled ones.
+        queue.push(flags(3), Placement::AfterOriginal, Duration::ZERO);
+        queue.push(flags(2), Placement::BeforeOriginal, Duration::ZERO);
+        queue.push(key(1), Placement::AfterOriginal, Duration::ZERO);
+        assert_eq!(queue.last_flags(), Some(3));
+    }
+
+    #[test]
+    fn test_clear_keeps_original() {
+        let mut queue = OutputQueue::default();
+        queue.push(key(1), Placement::BeforeOriginal, Duration::from_millis(50));
+        let original = OriginalEvent(std::ptr::null_mut());
+        let pending = queue.take_pending();
+        queue.schedule(pending, Some(original), Instant::now());
+        queue.push(key(2), Placement::AfterOriginal, Duration::ZERO);
+
+        assert_eq!(queue.clear(), vec![SyntheticEvent::Original(original)]);
+        assert!(queue.take_pending().after.is_empty());
+        assert!(queue.scheduled.is_empty());
+    }
+}
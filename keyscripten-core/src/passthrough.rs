@@ -0,0 +1,118 @@
+use crate::event::Event;
+use crate::hotkey::HotKey;
+use crate::modifier_state::resync_modifiers;
+use crate::output;
+use apple_sys::CoreGraphics::CGKeyCode;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Mutex;
+
+// In passthrough mode, every event goes to the application unchanged and the plugins don't run.
+static KILLED: AtomicBool = AtomicBool::new(false);
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref LISTENER: Mutex<Option<Sender<PassthroughState>>> = Mutex::new(None);
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct PassthroughState {
+    // The kill switch was pressed.
+    pub killed: bool,
+    // The user paused the plugins.
+    pub paused: bool,
+}
+
+impl PassthroughState {
+    pub fn is_passthrough(&self) -> bool {
+        self.killed || self.paused
+    }
+}
+
+/// Register the channel notified with the new state, when the passthrough mode is switched.
+pub fn set_listener(tx: Sender<PassthroughState>) {
+    match LISTENER.lock() {
+        Ok(mut listener) => *listener = Some(tx),
+        Err(err) => log::error!("Cannot get lock for passthrough listener: {:?}", err),
+    }
+}
+
+fn notify() {
+    match LISTENER.lock() {
+        Ok(listener) => {
+            if let Some(tx) = listener.as_ref() {
+                if let Err(err) = tx.send(get_state()) {
+                    log::error!("Cannot notify the passthrough mode: {:?}", err);
+                }
+            }
+        }
+        Err(err) => log::error!("Cannot get lock for passthrough listener: {:?}", err),
+    }
+}
+
+// Drop the events the plugins are going to send, and give the modifiers back to the keyboard.
+fn stop_plugins() {
+    output::clear();
+    resync_modifiers();
+    output::flush(None);
+}
+
+pub fn get_state() -> PassthroughState {
+    PassthroughState {
+        killed: KILLED.load(Ordering::SeqCst),
+        paused: PAUSED.load(Ordering::SeqCst),
+    }
+}
+
+pub fn is_passthrough() -> bool {
+    get_state().is_passthrough()
+}
+
+/// Called when the kill switch was pressed.
+pub fn engage_kill_switch() {
+    if KILLED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    log::warn!("Kill switch was engaged. All events pass through.");
+    stop_plugins();
+    notify();
+}
+
+pub fn set_paused(paused: bool) {
+    if PAUSED.swap(paused, Ordering::SeqCst) == paused {
+        return;
+    }
+    log::info!("Set paused: {}", paused);
+    if paused {
+        stop_plugins();
+    }
+    notify();
+}
+
+/// Run the plugins again, after the kill switch or the pause.
+pub fn resume() {
+    let killed = KILLED.swap(false, Ordering::SeqCst);
+    let paused = PAUSED.swap(false, Ordering::SeqCst);
+    if killed || paused {
+        log::info!("Resumed the plugins.");
+        notify();
+    }
+}
+
+/// Global hotkey to toggle the pause. It works without the plugins.
+pub struct PauseHotKey(HotKey);
+
+impl PauseHotKey {
+    /// `s` is the hotkey in the `C-S-M-p` format.
+    pub fn from_str(s: &str) -> anyhow::Result<PauseHotKey> {
+        Ok(PauseHotKey(HotKey::from_str(s)?))
+    }
+
+    pub fn matches(&self, event: &Event) -> bool {
+        event.event_type == "keyDown"
+            && !event.autorepeat
+            && self.0.matches(event.flags, event.keycode as CGKeyCode)
+    }
+}
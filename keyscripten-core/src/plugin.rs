@@ -1,17 +1,54 @@
+use crate::file_security;
+use crate::plugin_manifest::PluginManifest;
 use crate::APP_NAME;
 use anyhow::anyhow;
 use serde_json::json;
+use std::collections::HashSet;
 use std::fs;
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
+
+// The manifest of the plugin package.
+const PACKAGE_MANIFEST: &str = "manifest.json";
 
 #[derive(Debug)]
 pub struct PluginSnippet {
     pub filename: String,
     pub src: String,
+    // The scripts of the package evaluated before `src`. (path in the package, src)
+    pub preludes: Vec<(String, String)>,
+    // The directory of the plugin package. `readPluginResource` reads the files under it.
+    pub package_dir: Option<PathBuf>,
+    // The path of `src`. The relative imports are resolved from it.
+    pub path: Option<PathBuf>,
+    // True if `src` is an ES module.
+    pub module: bool,
+}
+
+impl PluginSnippet {
+    pub fn new(filename: String, src: String) -> PluginSnippet {
+        PluginSnippet {
+            filename,
+            src,
+            preludes: vec![],
+            package_dir: None,
+            path: None,
+            module: false,
+        }
+    }
+}
+
+/// A file in the plugin package.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PackageFile {
+    // relative to the package directory.
+    pub path: String,
+    pub content: Vec<u8>,
 }
 
 pub struct Plugins {
     basedir: String,
+    // `config.json`, next to the plugins directory.
+    config_path: PathBuf,
 }
 impl Plugins {
     pub fn new() -> anyhow::Result<Plugins> {
@@ -23,9 +60,47 @@ impl Plugins {
     }
 
     pub fn new_with_basedir(basedir: String) -> Plugins {
-        Plugins { basedir }
+        let config_path = Path::new(&basedir).with_file_name("config.json");
+        Plugins {
+            basedir,
+            config_path,
+        }
+    }
+
+    pub fn config_path(&self) -> &Path {
+        &self.config_path
+    }
+
+    // The path of the script or the package directory. It never goes out of the plugins directory.
+    fn plugin_path(&self, filename: &str) -> anyhow::Result<PathBuf> {
+        validate_filename(filename)?;
+        let basedir = Path::new(&self.basedir);
+        let path = basedir.join(filename);
+        // The symlink may point to the outside.
+        if path.symlink_metadata().is_ok() {
+            ensure_inside(basedir, &path)?;
+        }
+        Ok(path)
+    }
+
+    // The plugin package is a directory with the manifest, under the plugins directory.
+    fn package_dir(&self, filename: &str) -> Option<PathBuf> {
+        let path = self.plugin_path(filename).ok()?;
+        if path.join(PACKAGE_MANIFEST).is_file() {
+            Some(path)
+        } else {
+            None
+        }
+    }
+
+    fn read_package_manifest(package_dir: &Path) -> anyhow::Result<PluginManifest> {
+        let path = package_dir.join(PACKAGE_MANIFEST);
+        let json = fs::read_to_string(&path)
+            .map_err(|err| anyhow!("Cannot read {:?}: {:?}", path, err))?;
+        PluginManifest::from_json(&json).map_err(|err| anyhow!("{:?}: {}", path, err))
     }
 
+    /// Lists the script files and the plugin package directories.
     pub fn list(&self) -> anyhow::Result<Vec<String>> {
         let plugins_dir = Path::new(&self.basedir);
 
@@ -38,16 +113,18 @@ impl Plugins {
             let entry = entry?;
             let path = entry.path();
             log::info!("Found {:?}", path);
+            let file_name = match path.file_name().and_then(|file_name| file_name.to_str()) {
+                Some(file_name) => file_name,
+                None => continue,
+            };
             if path.is_file() {
                 if let Some(ext) = path.extension() {
-                    if ext == "js" {
-                        if let Some(file_name) = path.file_name() {
-                            if let Some(file_name_str) = file_name.to_str() {
-                                filenames.push(file_name_str.to_string());
-                            }
-                        }
+                    if ext == "js" || ext == "mjs" {
+                        filenames.push(file_name.to_string());
                     }
                 }
+            } else if !file_name.starts_with('.') && self.package_dir(file_name).is_some() {
+                filenames.push(file_name.to_string());
             }
         }
 
@@ -55,6 +132,7 @@ impl Plugins {
     }
 
     pub fn add(&self, plugin_id: String, name: String, description: String) -> anyhow::Result<()> {
+        validate_plugin_id(&plugin_id)?;
         let content = include_str!("../resources/plugin-template.js")
             .replace("/*ID*/{}", &json!(plugin_id).to_string())
             .replace("/*NAME*/{}", &json!(name).to_string())
@@ -62,13 +140,26 @@ impl Plugins {
         self.write(format!("{}.js", plugin_id), content)
     }
 
+    /// Write the script. For the plugin package, it's the entry point.
     pub fn write(&self, filename: String, content: String) -> anyhow::Result<()> {
         let plugins = Path::new(&self.basedir);
         if !plugins.exists() {
             fs::create_dir_all(plugins)
                 .map_err(|err| anyhow!("Cannot create plugins directory: {:?}", err))?;
         }
-        let pluginpath = plugins.join(filename);
+        let pluginpath = match self.package_dir(&filename) {
+            Some(package_dir) => {
+                let manifest = Self::read_package_manifest(&package_dir)?;
+                resolve_in_package(&package_dir, manifest.entry_point())?
+            }
+            None => {
+                let path = self.plugin_path(&filename)?;
+                if path.symlink_metadata().is_err() {
+                    validate_new_filename(&filename)?;
+                }
+                path
+            }
+        };
         log::info!("Writing plugin: {:?}", pluginpath);
         fs::write(pluginpath.as_path(), content).map_err(|err| {
             anyhow!(
@@ -80,23 +171,137 @@ impl Plugins {
         Ok(())
     }
 
+    /// Read the script. For the plugin package, `src` is the entry point.
     pub fn read(&self, filename: String) -> anyhow::Result<PluginSnippet> {
         let plugins = Path::new(&self.basedir);
         if !plugins.exists() {
             return Err(anyhow!("Missing plugin: {:?}", filename));
         }
 
-        let pluginpath = plugins.join(filename.clone());
+        if let Some(package_dir) = self.package_dir(&filename) {
+            log::info!("Reading plugin package: {:?}", package_dir);
+            let manifest = Self::read_package_manifest(&package_dir)?;
+            let read = |path: &str| -> anyhow::Result<String> {
+                let path = resolve_in_package(&package_dir, path)?;
+                fs::read_to_string(&path)
+                    .map_err(|err| anyhow!("Cannot read {:?}: {:?}", path, err))
+            };
+            let mut preludes = Vec::new();
+            for script in &manifest.scripts {
+                preludes.push((script.clone(), read(script)?));
+            }
+            let src = read(manifest.entry_point())?;
+            return Ok(PluginSnippet {
+                filename,
+                src,
+                preludes,
+                path: Some(resolve_in_package(&package_dir, manifest.entry_point())?),
+                module: manifest.is_module() || manifest.entry_point().ends_with(".mjs"),
+                package_dir: Some(package_dir),
+            });
+        }
+
+        let pluginpath = self.plugin_path(&filename)?;
         log::info!("Reading plugin: {:?}", pluginpath);
         let src = fs::read_to_string(pluginpath.as_path())?;
-        Ok(PluginSnippet { filename, src })
+        let module = filename.ends_with(".mjs");
+        Ok(PluginSnippet {
+            path: Some(pluginpath),
+            module,
+            ..PluginSnippet::new(filename, src)
+        })
+    }
+
+    pub fn plugins_dir(&self) -> &Path {
+        Path::new(&self.basedir)
+    }
+
+    /// The shared library folder. It's next to the plugins directory.
+    pub fn lib_dir(&self) -> PathBuf {
+        Path::new(&self.basedir).with_file_name("lib")
+    }
+
+    /// Read every file in the plugin package.
+    pub fn read_package(&self, filename: String) -> anyhow::Result<Vec<PackageFile>> {
+        let package_dir = self
+            .package_dir(&filename)
+            .ok_or_else(|| anyhow!("{} is not a plugin package", filename))?;
+        let mut files = Vec::new();
+        collect_package_files(&package_dir, &package_dir, &mut files)?;
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(files)
+    }
+
+    /// Replace the whole plugin package. The files are written in the temporary directory
+    /// first, so the plugin never sees the half-written package.
+    pub fn write_package(&self, filename: String, files: Vec<PackageFile>) -> anyhow::Result<()> {
+        if !files.iter().any(|file| file.path == PACKAGE_MANIFEST) {
+            return Err(anyhow!("The plugin package must have {}", PACKAGE_MANIFEST));
+        }
+        validate_new_filename(&filename)?;
+
+        let package_dir = self.plugin_path(&filename)?;
+        let plugins = Path::new(&self.basedir);
+        let tmp_dir = plugins.join(format!(".{}.tmp", filename));
+        if tmp_dir.exists() {
+            fs::remove_dir_all(&tmp_dir)?;
+        }
+        fs::create_dir_all(&tmp_dir)
+            .map_err(|err| anyhow!("Cannot create {:?}: {:?}", tmp_dir, err))?;
+        if let Err(err) = write_package_files(&tmp_dir, &files) {
+            if let Err(err) = fs::remove_dir_all(&tmp_dir) {
+                log::error!("Cannot remove {:?}: {:?}", tmp_dir, err);
+            }
+            return Err(err);
+        }
+
+        // Keep the old package until the new one is in place.
+        let backup_dir = plugins.join(format!(".{}.bak", filename));
+        if backup_dir.exists() {
+            fs::remove_dir_all(&backup_dir)?;
+        }
+        let has_backup = package_dir.exists();
+        if has_backup {
+            fs::rename(&package_dir, &backup_dir)
+                .map_err(|err| anyhow!("Cannot rename {:?}: {:?}", package_dir, err))?;
+        }
+        log::info!("Writing plugin package: {:?}", package_dir);
+        if let Err(err) = fs::rename(&tmp_dir, &package_dir) {
+            if has_backup {
+                if let Err(err) = fs::rename(&backup_dir, &package_dir) {
+                    log::error!("Cannot restore {:?}: {:?}", package_dir, err);
+                }
+            }
+            if let Err(err) = fs::remove_dir_all(&tmp_dir) {
+                log::error!("Cannot remove {:?}: {:?}", tmp_dir, err);
+            }
+            return Err(anyhow!("Cannot rename {:?}: {:?}", tmp_dir, err));
+        }
+        if has_backup {
+            if let Err(err) = fs::remove_dir_all(&backup_dir) {
+                log::error!("Cannot remove {:?}: {:?}", backup_dir, err);
+            }
+        }
+        Ok(())
     }
 
     pub fn read_user_scripts(&self) -> anyhow::Result<Vec<PluginSnippet>> {
+        // Other users must not be able to put the code or change the configuration.
+        if let Some(config_dir) = Path::new(&self.basedir).parent() {
+            file_security::check_file(config_dir)?;
+        }
+        file_security::check_file(Path::new(&self.basedir))?;
+        file_security::check_file(&self.config_path)?;
+        file_security::check_tree(&self.lib_dir())?;
+
         match self.list() {
             Ok(filenames) => {
                 let mut results = Vec::new();
                 for filename in filenames {
+                    if let Err(err) = self.check_security(&filename) {
+                        log::error!("Cannot load {}: {:?}", filename, err);
+                        continue;
+                    }
                     match self.read(filename.clone()) {
                         Ok(snippet) => {
                             results.push(snippet);
@@ -112,13 +317,105 @@ impl Plugins {
         }
     }
 
+    // Check the files evaluated for the plugin.
+    fn check_security(&self, filename: &str) -> anyhow::Result<()> {
+        match self.package_dir(filename) {
+            Some(package_dir) => file_security::check_tree(&package_dir),
+            None => {
+                file_security::check_file(&self.plugin_path(filename)?)?;
+                // The manifest decides the settings the plugin gets.
+                file_security::check_file(&self.sidecar_path(filename))
+            }
+        }
+    }
+
+    // The manifest of the script. `foo.json` for `foo.js`.
+    fn sidecar_path(&self, filename: &str) -> PathBuf {
+        Path::new(&self.basedir)
+            .join(filename)
+            .with_extension("json")
+    }
+
+    /// Read the manifest of the script. The sidecar JSON(`foo.json` for `foo.js`) takes
+    /// precedence over the leading comment block. The plugin package always has the manifest.
+    pub fn read_manifest(&self, snippet: &PluginSnippet) -> anyhow::Result<Option<PluginManifest>> {
+        if let Some(package_dir) = &snippet.package_dir {
+            return Self::read_package_manifest(package_dir).map(Some);
+        }
+
+        let sidecar = self.sidecar_path(&snippet.filename);
+        if sidecar.is_file() {
+            let json = fs::read_to_string(&sidecar)
+                .map_err(|err| anyhow!("Cannot read {:?}: {:?}", sidecar, err))?;
+            return PluginManifest::from_json(&json)
+                .map(Some)
+                .map_err(|err| anyhow!("{:?}: {}", sidecar, err));
+        }
+        PluginManifest::from_header(&snippet.src)
+            .map_err(|err| anyhow!("{}: {}", snippet.filename, err))
+    }
+
+    /// List the manifests of the plugins, without running them.
+    pub fn list_manifests(&self) -> anyhow::Result<Vec<PluginManifest>> {
+        let mut manifests = Vec::new();
+        for snippet in self.read_user_scripts()? {
+            match self.read_manifest(&snippet) {
+                Ok(Some(manifest)) => manifests.push(manifest),
+                Ok(None) => log::debug!("{} doesn't have the manifest", snippet.filename),
+                Err(err) => log::error!("Cannot read manifest: {:?}", err),
+            }
+        }
+        Ok(manifests)
+    }
+
+    /// Find the file of the plugin by the id in its manifest, without running it.
+    pub fn find_filename_by_manifest(&self, plugin_id: &str) -> anyhow::Result<Option<String>> {
+        for snippet in self.read_user_scripts()? {
+            if let Ok(Some(manifest)) = self.read_manifest(&snippet) {
+                if manifest.id == plugin_id {
+                    return Ok(Some(snippet.filename));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// The ids of the installed plugins, without running them. The script without the manifest is
+    /// assumed to use its file name as the id.
+    pub fn installed_plugin_ids(&self) -> anyhow::Result<HashSet<String>> {
+        let mut ids: HashSet<String> = self
+            .list()?
+            .into_iter()
+            .map(|filename| {
+                filename
+                    .strip_suffix(".js")
+                    .or_else(|| filename.strip_suffix(".mjs"))
+                    .unwrap_or(&filename)
+                    .to_string()
+            })
+            .collect();
+        ids.extend(
+            self.list_manifests()?
+                .into_iter()
+                .map(|manifest| manifest.id),
+        );
+        Ok(ids)
+    }
+
+    /// Delete the script, or the whole plugin package.
     pub fn delete(&self, filename: String) -> anyhow::Result<()> {
         let plugins = Path::new(&self.basedir);
         if !plugins.exists() {
             return Err(anyhow!("Cannot instantiate Path object: {:?}", filename));
         }
 
-        let pluginpath = plugins.join(filename);
+        if let Some(package_dir) = self.package_dir(&filename) {
+            log::info!("Deleting plugin package: {:?}", package_dir);
+            return fs::remove_dir_all(&package_dir)
+                .map_err(|err| anyhow!("Cannot remove directory({:?}): {:?}", package_dir, err));
+        }
+
+        let pluginpath = self.plugin_path(&filename)?;
         let pluginpath = pluginpath.as_path();
         log::info!("Deleting plugin: {:?}", pluginpath);
         fs::remove_file(pluginpath)
@@ -126,6 +423,124 @@ impl Plugins {
     }
 }
 
+/// Resolve the relative path in the plugin package. The path can't go out of the package.
+pub(crate) fn resolve_in_package(package_dir: &Path, path: &str) -> anyhow::Result<PathBuf> {
+    let relative = Path::new(path);
+    if path.is_empty()
+        || !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(anyhow!("Invalid path in the plugin package: {:?}", path));
+    }
+    let path = package_dir.join(relative);
+    if path.symlink_metadata().is_ok() {
+        ensure_inside(package_dir, &path)?;
+    }
+    Ok(path)
+}
+
+// The plugin ids are used as the file names.
+const MAX_NAME_LEN: usize = 128;
+
+fn is_valid_name(name: &str) -> bool {
+    name.len() <= MAX_NAME_LEN
+        && name
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphanumeric())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-')
+}
+
+/// The plugin id is alphanumeric, `.`, `_` and `-`, and starts with the alphanumeric.
+pub fn validate_plugin_id(plugin_id: &str) -> anyhow::Result<()> {
+    if !is_valid_name(plugin_id) {
+        return Err(anyhow!(
+            "Invalid plugin id: {:?}. Use alphanumeric, '.', '_' and '-'.",
+            plugin_id
+        ));
+    }
+    Ok(())
+}
+
+// The script file name or the package directory name, directly under the plugins directory.
+// The existing files may have any name, e.g. with spaces. The new files are checked by
+// `validate_new_filename`.
+fn validate_filename(filename: &str) -> anyhow::Result<()> {
+    let mut components = Path::new(filename).components();
+    let is_plain = matches!(components.next(), Some(Component::Normal(_)))
+        && components.next().is_none()
+        && !filename.starts_with('.')
+        && !filename.contains(['/', '\\']);
+    if !is_plain {
+        return Err(anyhow!("Invalid plugin file name: {:?}", filename));
+    }
+    Ok(())
+}
+
+// The name of the file created by the app.
+fn validate_new_filename(filename: &str) -> anyhow::Result<()> {
+    if !is_valid_name(filename) {
+        return Err(anyhow!(
+            "Invalid plugin file name: {:?}. Use alphanumeric, '.', '_' and '-'.",
+            filename
+        ));
+    }
+    Ok(())
+}
+
+// `path` must be in `dir`, after resolving the symlinks.
+fn ensure_inside(dir: &Path, path: &Path) -> anyhow::Result<()> {
+    let dir = dir
+        .canonicalize()
+        .map_err(|err| anyhow!("Cannot canonicalize {:?}: {:?}", dir, err))?;
+    let canonical = path
+        .canonicalize()
+        .map_err(|err| anyhow!("Cannot canonicalize {:?}: {:?}", path, err))?;
+    if !canonical.starts_with(&dir) {
+        return Err(anyhow!("{:?} is out of {:?}", path, dir));
+    }
+    Ok(())
+}
+
+fn write_package_files(package_dir: &Path, files: &[PackageFile]) -> anyhow::Result<()> {
+    for file in files {
+        let path = resolve_in_package(package_dir, &file.path)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, &file.content)
+            .map_err(|err| anyhow!("Cannot write {:?}: {:?}", path, err))?;
+    }
+    Ok(())
+}
+
+fn collect_package_files(
+    package_dir: &Path,
+    dir: &Path,
+    files: &mut Vec<PackageFile>,
+) -> anyhow::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_package_files(package_dir, &path, files)?;
+        } else if path.is_file() {
+            let relative = path
+                .strip_prefix(package_dir)?
+                .to_str()
+                .ok_or_else(|| anyhow!("Invalid file name: {:?}", path))?
+                .to_string();
+            files.push(PackageFile {
+                path: relative,
+                content: fs::read(&path)?,
+            });
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -199,6 +614,32 @@ mod tests {
         assert!(temp_path.join("plugin_two.js").exists());
     }
 
+    #[test]
+    fn test_installed_plugin_ids() {
+        initialize_logger();
+
+        let temp_dir = TempDir::with_prefix("installed_plugin_ids").unwrap();
+        let temp_path = temp_dir.path();
+        let plugins = Plugins::new_with_basedir(temp_path.to_str().unwrap().to_string());
+
+        plugins
+            .add(
+                "with-manifest".to_string(),
+                "WithManifest".to_string(),
+                "".to_string(),
+            )
+            .unwrap();
+        plugins
+            .write("legacy.mjs".to_string(), "code".to_string())
+            .unwrap();
+
+        let ids = plugins.installed_plugin_ids().unwrap();
+        assert_eq!(
+            ids,
+            HashSet::from(["with-manifest".to_string(), "legacy".to_string()])
+        );
+    }
+
     #[test]
     fn test_write() {
         initialize_logger();
@@ -251,4 +692,285 @@ mod tests {
         // removed
         assert!(!temp_path.join("hello.js").exists());
     }
+
+    #[test]
+    fn test_list_manifests() {
+        initialize_logger();
+
+        let temp_dir = TempDir::with_prefix("manifest").unwrap();
+        let temp_path = temp_dir.path();
+        let plugins = Plugins::new_with_basedir(temp_path.to_str().unwrap().to_string());
+
+        plugins
+            .add(
+                "with_header".to_string(),
+                "WithHeader".to_string(),
+                "Description".to_string(),
+            )
+            .unwrap();
+        plugins
+            .write("sidecar.js".to_string(), "code".to_string())
+            .unwrap();
+        fs::write(
+            temp_path.join("sidecar.json"),
+            r#"{"id": "sidecar", "name": "Sidecar"}"#,
+        )
+        .unwrap();
+        plugins
+            .write("legacy.js".to_string(), "code".to_string())
+            .unwrap();
+
+        let mut ids: Vec<String> = plugins
+            .list_manifests()
+            .unwrap()
+            .into_iter()
+            .map(|manifest| manifest.id)
+            .collect();
+        ids.sort();
+        assert_eq!(ids, vec!["sidecar", "with_header"]);
+    }
+
+    #[test]
+    fn test_find_filename_by_manifest() {
+        initialize_logger();
+
+        let temp_dir = TempDir::with_prefix("find_filename").unwrap();
+        let temp_path = temp_dir.path();
+        let plugins = Plugins::new_with_basedir(temp_path.to_str().unwrap().to_string());
+
+        plugins
+            .write("sidecar.js".to_string(), "code".to_string())
+            .unwrap();
+        fs::write(
+            temp_path.join("sidecar.json"),
+            r#"{"id": "renamed", "name": "Sidecar"}"#,
+        )
+        .unwrap();
+        plugins
+            .write("legacy.js".to_string(), "code".to_string())
+            .unwrap();
+
+        assert_eq!(
+            plugins.find_filename_by_manifest("renamed").unwrap(),
+            Some("sidecar.js".to_string())
+        );
+        assert_eq!(plugins.find_filename_by_manifest("legacy").unwrap(), None);
+    }
+
+    fn write_test_package(plugins: &Plugins) {
+        plugins
+            .write_package(
+                "wordlist".to_string(),
+                vec![
+                    PackageFile {
+                        path: "manifest.json".to_string(),
+                        content:
+                            br#"{"id": "wordlist", "name": "Word list", "scripts": ["util.js"]}"#
+                                .to_vec(),
+                    },
+                    PackageFile {
+                        path: "util.js".to_string(),
+                        content: b"var util = 1;".to_vec(),
+                    },
+                    PackageFile {
+                        path: "index.js".to_string(),
+                        content: b"main".to_vec(),
+                    },
+                    PackageFile {
+                        path: "data/words.txt".to_string(),
+                        content: b"hello".to_vec(),
+                    },
+                ],
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn test_package() {
+        initialize_logger();
+
+        let temp_dir = TempDir::with_prefix("package").unwrap();
+        let temp_path = temp_dir.path();
+        let plugins = Plugins::new_with_basedir(temp_path.to_str().unwrap().to_string());
+        write_test_package(&plugins);
+        // not a package
+        fs::create_dir_all(temp_path.join("notes")).unwrap();
+
+        assert_eq!(plugins.list().unwrap(), vec!["wordlist"]);
+
+        let snippet = plugins.read("wordlist".to_string()).unwrap();
+        assert_eq!(snippet.src, "main");
+        assert_eq!(
+            snippet.preludes,
+            vec![("util.js".to_string(), "var util = 1;".to_string())]
+        );
+        assert_eq!(snippet.package_dir, Some(temp_path.join("wordlist")));
+        assert_eq!(
+            plugins.read_manifest(&snippet).unwrap().unwrap().id,
+            "wordlist"
+        );
+
+        // write the entry point
+        plugins
+            .write("wordlist".to_string(), "updated".to_string())
+            .unwrap();
+        let files = plugins.read_package("wordlist".to_string()).unwrap();
+        let paths: Vec<&str> = files.iter().map(|file| file.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec!["data/words.txt", "index.js", "manifest.json", "util.js"]
+        );
+        assert_eq!(files[1].content, b"updated");
+
+        // replace the package. The old one is removed after the new one is in place.
+        write_test_package(&plugins);
+        let snippet = plugins.read("wordlist".to_string()).unwrap();
+        assert_eq!(snippet.src, "main");
+        assert!(!temp_path.join(".wordlist.bak").exists());
+        assert!(!temp_path.join(".wordlist.tmp").exists());
+
+        plugins.delete("wordlist".to_string()).unwrap();
+        assert!(!temp_path.join("wordlist").exists());
+    }
+
+    #[test]
+    fn test_write_package_rejects_escape() {
+        initialize_logger();
+
+        let temp_dir = TempDir::with_prefix("package").unwrap();
+        let plugins = Plugins::new_with_basedir(temp_dir.path().to_str().unwrap().to_string());
+        let result = plugins.write_package(
+            "evil".to_string(),
+            vec![
+                PackageFile {
+                    path: "manifest.json".to_string(),
+                    content: b"{}".to_vec(),
+                },
+                PackageFile {
+                    path: "../escaped.js".to_string(),
+                    content: b"code".to_vec(),
+                },
+            ],
+        );
+        assert!(result.is_err());
+        assert!(!temp_dir.path().join("escaped.js").exists());
+        assert!(!temp_dir.path().join(".evil.tmp").exists());
+    }
+
+    #[test]
+    fn test_validate_plugin_id() {
+        assert!(validate_plugin_id("test_plugin").is_ok());
+        assert!(validate_plugin_id("builtin.dynamicmacro").is_ok());
+        assert!(validate_plugin_id("my-plugin2").is_ok());
+
+        assert!(validate_plugin_id("").is_err());
+        assert!(validate_plugin_id("../../.zshrc").is_err());
+        assert!(validate_plugin_id(".hidden").is_err());
+        assert!(validate_plugin_id("a/b").is_err());
+        assert!(validate_plugin_id("a\\b").is_err());
+        assert!(validate_plugin_id("with space").is_err());
+        assert!(validate_plugin_id(&"a".repeat(MAX_NAME_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn test_reject_path_traversal() {
+        initialize_logger();
+
+        let temp_dir = TempDir::with_prefix("traversal").unwrap();
+        let basedir = temp_dir.path().join("plugins");
+        let plugins = Plugins::new_with_basedir(basedir.to_str().unwrap().to_string());
+
+        assert!(plugins
+            .add(
+                "../../.zshrc".to_string(),
+                "Evil".to_string(),
+                "".to_string()
+            )
+            .is_err());
+        assert!(plugins
+            .write("../escaped.js".to_string(), "code".to_string())
+            .is_err());
+        assert!(!temp_dir.path().join("escaped.js").exists());
+
+        fs::write(temp_dir.path().join("secret.js"), "secret").unwrap();
+        assert!(plugins.read("../secret.js".to_string()).is_err());
+        assert!(plugins.delete("../secret.js".to_string()).is_err());
+        assert!(temp_dir.path().join("secret.js").exists());
+    }
+
+    #[test]
+    fn test_existing_file_with_space() {
+        initialize_logger();
+
+        let temp_dir = TempDir::with_prefix("space").unwrap();
+        let temp_path = temp_dir.path();
+        let plugins = Plugins::new_with_basedir(temp_path.to_str().unwrap().to_string());
+
+        // copied by the user
+        fs::write(temp_path.join("my plugin.js"), "code").unwrap();
+        assert_eq!(plugins.list().unwrap(), vec!["my plugin.js"]);
+        assert_eq!(
+            plugins.read("my plugin.js".to_string()).unwrap().src,
+            "code"
+        );
+        plugins
+            .write("my plugin.js".to_string(), "updated".to_string())
+            .unwrap();
+
+        // the app doesn't create such names.
+        assert!(plugins
+            .write("new plugin.js".to_string(), "code".to_string())
+            .is_err());
+        assert!(plugins.read("..".to_string()).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_reject_insecure_sidecar() {
+        use std::os::unix::fs::PermissionsExt;
+        initialize_logger();
+
+        let temp_dir = TempDir::with_prefix("sidecar").unwrap();
+        let temp_path = temp_dir.path();
+        let plugins = Plugins::new_with_basedir(temp_path.to_str().unwrap().to_string());
+        plugins
+            .write("sidecar.js".to_string(), "code".to_string())
+            .unwrap();
+        fs::write(
+            temp_path.join("sidecar.json"),
+            r#"{"id": "sidecar", "name": "Sidecar"}"#,
+        )
+        .unwrap();
+        assert_eq!(plugins.read_user_scripts().unwrap().len(), 1);
+
+        fs::set_permissions(
+            temp_path.join("sidecar.json"),
+            fs::Permissions::from_mode(0o666),
+        )
+        .unwrap();
+        assert!(plugins.read_user_scripts().unwrap().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_reject_symlink_escape() {
+        initialize_logger();
+
+        let temp_dir = TempDir::with_prefix("symlink").unwrap();
+        let basedir = temp_dir.path().join("plugins");
+        fs::create_dir_all(&basedir).unwrap();
+        let plugins = Plugins::new_with_basedir(basedir.to_str().unwrap().to_string());
+
+        fs::write(temp_dir.path().join("secret.js"), "secret").unwrap();
+        std::os::unix::fs::symlink(temp_dir.path().join("secret.js"), basedir.join("link.js"))
+            .unwrap();
+        assert!(plugins.read("link.js".to_string()).is_err());
+        assert!(plugins
+            .write("link.js".to_string(), "overwritten".to_string())
+            .is_err());
+        assert_eq!(
+            fs::read_to_string(temp_dir.path().join("secret.js")).unwrap(),
+            "secret"
+        );
+    }
 }
@@ -0,0 +1,194 @@
+use crate::config_schema::ConfigItem;
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// The plugin API version provided by this application.
+pub const PLUGIN_API_VERSION: u32 = 1;
+
+const HEADER_BEGIN: &str = "// ==KeyScriptenPlugin==";
+const HEADER_END: &str = "// ==/KeyScriptenPlugin==";
+
+/// Metadata of the plugin. It's read without executing the code.
+///
+/// It's written in the leading comment block of the script, or in the sidecar JSON file.
+///
+/// ```javascript
+/// // ==KeyScriptenPlugin==
+/// // {
+/// //   "id": "my-plugin",
+/// //   "name": "My plugin",
+/// //   "version": "1.0.0"
+/// // }
+/// // ==/KeyScriptenPlugin==
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PluginManifest {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub version: Option<String>,
+    pub author: Option<String>,
+    #[serde(default = "default_api_version")]
+    pub api_version: u32,
+    #[serde(default)]
+    pub config_schema: Vec<ConfigItem>,
+    // The entry point of the plugin package. The default is `index.js`.
+    pub main: Option<String>,
+    // The scripts of the plugin package, evaluated before the entry point.
+    #[serde(default)]
+    pub scripts: Vec<String>,
+    // "module" if the plugin is an ES module.
+    #[serde(rename = "type")]
+    pub plugin_type: Option<String>,
+    // The old ids of the plugin. Their settings are moved to the current id.
+    #[serde(default)]
+    pub previous_ids: Vec<String>,
+    // The renamed config keys. (old key -> new key)
+    #[serde(default)]
+    pub renamed_config_keys: HashMap<String, String>,
+}
+
+fn default_api_version() -> u32 {
+    PLUGIN_API_VERSION
+}
+
+impl PluginManifest {
+    pub fn from_json(json: &str) -> anyhow::Result<PluginManifest> {
+        let manifest: PluginManifest =
+            serde_json::from_str(json).map_err(|err| anyhow!("Invalid manifest: {}", err))?;
+        manifest.validate()?;
+        Ok(manifest)
+    }
+
+    /// Parse the leading comment block of the script. Returns None if the script doesn't have it.
+    pub fn from_header(src: &str) -> anyhow::Result<Option<PluginManifest>> {
+        let mut lines = src
+            .lines()
+            .map(|line| line.trim())
+            .skip_while(|line| line.is_empty());
+        if lines.next() != Some(HEADER_BEGIN) {
+            return Ok(None);
+        }
+
+        let mut json = String::new();
+        for line in lines {
+            if line == HEADER_END {
+                return Ok(Some(Self::from_json(&json)?));
+            }
+            match line.strip_prefix("//") {
+                Some(line) => {
+                    json.push_str(line);
+                    json.push('\n');
+                }
+                None => return Err(anyhow!("Manifest header must be line comments: {}", line)),
+            }
+        }
+        Err(anyhow!("Missing {} in the manifest header", HEADER_END))
+    }
+
+    pub fn entry_point(&self) -> &str {
+        self.main.as_deref().unwrap_or("index.js")
+    }
+
+    pub fn is_module(&self) -> bool {
+        self.plugin_type.as_deref() == Some("module")
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.id.is_empty() {
+            return Err(anyhow!("Manifest must have the plugin id"));
+        }
+        for item in &self.config_schema {
+            item.validate()?;
+        }
+        if self.api_version > PLUGIN_API_VERSION {
+            return Err(anyhow!(
+                "{} requires plugin API version {}, but this application supports {}",
+                self.id,
+                self.api_version,
+                PLUGIN_API_VERSION
+            ));
+        }
+        Ok(())
+    }
+
+    /// Check the plugins registered by `registerPlugin` on runtime.
+    pub fn check_registered(&self, registered: &[(String, String)]) -> anyhow::Result<()> {
+        match registered {
+            [(id, name)] if *id == self.id => {
+                if *name != self.name {
+                    return Err(anyhow!(
+                        "{} was registered as {:?}, but the manifest says {:?}",
+                        id,
+                        name,
+                        self.name
+                    ));
+                }
+                Ok(())
+            }
+            _ => Err(anyhow!(
+                "The manifest declares {}, but the script registered {:?}",
+                self.id,
+                registered.iter().map(|(id, _)| id).collect::<Vec<_>>()
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_header() -> anyhow::Result<()> {
+        let src = r#"
+// ==KeyScriptenPlugin==
+// {
+//   "id": "hello",
+//   "name": "Hello",
+//   "version": "1.0.0",
+//   "config_schema": [{"name": "hotkey", "type": "hotkey", "default": "C-t"}]
+// }
+// ==/KeyScriptenPlugin==
+registerPlugin("hello", "Hello", "", function () { return true; }, []);
+"#;
+        let manifest = PluginManifest::from_header(src)?.unwrap();
+        assert_eq!(manifest.id, "hello");
+        assert_eq!(manifest.version, Some("1.0.0".to_string()));
+        assert_eq!(manifest.api_version, PLUGIN_API_VERSION);
+        assert_eq!(manifest.config_schema.len(), 1);
+
+        // no header
+        assert_eq!(PluginManifest::from_header("registerPlugin();")?, None);
+
+        // unterminated
+        assert!(PluginManifest::from_header("// ==KeyScriptenPlugin==\n// {}\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(PluginManifest::from_json(r#"{"id": "", "name": "x"}"#).is_err());
+        assert!(
+            PluginManifest::from_json(r#"{"id": "x", "name": "x", "api_version": 999}"#).is_err()
+        );
+    }
+
+    #[test]
+    fn test_check_registered() -> anyhow::Result<()> {
+        let manifest = PluginManifest::from_json(r#"{"id": "hello", "name": "Hello"}"#)?;
+        assert!(manifest
+            .check_registered(&[("hello".to_string(), "Hello".to_string())])
+            .is_ok());
+        assert!(manifest
+            .check_registered(&[("hello".to_string(), "Bye".to_string())])
+            .is_err());
+        assert!(manifest
+            .check_registered(&[("other".to_string(), "Hello".to_string())])
+            .is_err());
+        assert!(manifest.check_registered(&[]).is_err());
+        Ok(())
+    }
+}
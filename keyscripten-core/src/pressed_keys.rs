@@ -0,0 +1,126 @@
+use crate::event::Event;
+use apple_sys::CoreGraphics::{
+    CGEventSourceKeyState, CGEventSourceStateID_kCGEventSourceStateHIDSystemState, CGKeyCode,
+};
+use lazy_static::lazy_static;
+use std::collections::HashSet;
+use std::sync::RwLock;
+
+lazy_static! {
+    static ref PRESSED_KEYS: RwLock<PressedKeys> = RwLock::new(PressedKeys::default());
+}
+
+/// Non-modifier keys currently held by the user.
+#[derive(Default, Debug)]
+struct PressedKeys {
+    keycodes: HashSet<i64>,
+}
+
+impl PressedKeys {
+    fn observe(&mut self, event: &Event) {
+        match event.event_type.as_str() {
+            "keyDown" => {
+                self.keycodes.insert(event.keycode);
+            }
+            "keyUp" => {
+                self.keycodes.remove(&event.keycode);
+            }
+            _ => {}
+        }
+    }
+
+    // The keyUp event can be lost, e.g. the focus moved to the secure input field.
+    // Drop the keys which are not held anymore.
+    fn reconcile<F>(&mut self, is_held: F)
+    where
+        F: Fn(i64) -> bool,
+    {
+        self.keycodes.retain(|keycode| is_held(*keycode));
+    }
+
+    fn sorted(&self) -> Vec<i64> {
+        let mut keycodes: Vec<i64> = self.keycodes.iter().cloned().collect();
+        keycodes.sort();
+        keycodes
+    }
+}
+
+fn is_held_on_hid(keycode: i64) -> bool {
+    unsafe {
+        CGEventSourceKeyState(
+            CGEventSourceStateID_kCGEventSourceStateHIDSystemState,
+            keycode as CGKeyCode,
+        )
+    }
+}
+
+/// Called when the user's input was received.
+pub fn observe_key_event(event: &Event) {
+    match PRESSED_KEYS.write() {
+        Ok(mut keys) => keys.observe(event),
+        Err(err) => log::error!("Cannot get lock for pressed keys: {:?}", err),
+    }
+}
+
+pub fn get_pressed_keys() -> Vec<i64> {
+    match PRESSED_KEYS.write() {
+        Ok(mut keys) => {
+            keys.reconcile(is_held_on_hid);
+            keys.sorted()
+        }
+        Err(err) => {
+            log::error!("Cannot get lock for pressed keys: {:?}", err);
+            vec![]
+        }
+    }
+}
+
+pub fn is_pressed(keycode: i64) -> bool {
+    get_pressed_keys().contains(&keycode)
+}
+
+/// Called when the event tap was restarted. The events were lost while the tap was disabled.
+pub fn clear_pressed_keys() {
+    match PRESSED_KEYS.write() {
+        Ok(mut keys) => keys.keycodes.clear(),
+        Err(err) => log::error!("Cannot get lock for pressed keys: {:?}", err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(event_type: &str, keycode: i64) -> Event {
+        Event {
+            event_type: event_type.to_string(),
+            keycode,
+            flags: 0,
+            autorepeat: false,
+            timestamp: 0,
+        }
+    }
+
+    #[test]
+    fn test_pressed_keys() {
+        let mut keys = PressedKeys::default();
+        keys.observe(&event("keyDown", 17));
+        keys.observe(&event("keyDown", 0));
+        keys.observe(&event("flagsChanged", 59));
+        assert_eq!(keys.sorted(), vec![0, 17]);
+
+        keys.observe(&event("keyUp", 17));
+        assert_eq!(keys.sorted(), vec![0]);
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let mut keys = PressedKeys::default();
+        keys.observe(&event("keyDown", 17));
+        keys.observe(&event("keyDown", 0));
+
+        // keyUp for 17 was lost.
+        keys.reconcile(|keycode| keycode != 17);
+        assert_eq!(keys.sorted(), vec![0]);
+    }
+}
@@ -0,0 +1,165 @@
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::RwLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// The plugin is disabled if it throws more than MAX_ERRORS times in ERROR_WINDOW.
+const MAX_ERRORS: usize = 5;
+const ERROR_WINDOW: Duration = Duration::from_secs(10);
+
+lazy_static! {
+    static ref PLUGIN_FAULTS: RwLock<HashMap<String, PluginFault>> = RwLock::new(HashMap::new());
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PluginFault {
+    pub plugin_id: String,
+    pub error_count: u64,
+    pub last_error: String,
+    pub last_stack: Option<String>,
+    pub quarantined: bool,
+    // in epoch seconds
+    pub quarantined_at: Option<u64>,
+    #[serde(skip)]
+    recent_errors: VecDeque<Instant>,
+}
+
+impl PluginFault {
+    fn new(plugin_id: &str) -> Self {
+        PluginFault {
+            plugin_id: plugin_id.to_string(),
+            error_count: 0,
+            last_error: String::new(),
+            last_stack: None,
+            quarantined: false,
+            quarantined_at: None,
+            recent_errors: VecDeque::new(),
+        }
+    }
+
+    fn record(&mut self, message: String, stack: Option<String>, now: Instant) {
+        self.error_count += 1;
+        self.last_error = message;
+        self.last_stack = stack;
+
+        self.recent_errors.push_back(now);
+        while let Some(at) = self.recent_errors.front() {
+            if now.duration_since(*at) > ERROR_WINDOW {
+                self.recent_errors.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        if self.recent_errors.len() > MAX_ERRORS {
+            self.quarantine();
+        }
+    }
+
+    fn quarantine(&mut self) {
+        if !self.quarantined {
+            self.quarantined = true;
+            self.quarantined_at = match SystemTime::now().duration_since(UNIX_EPOCH) {
+                Ok(n) => Some(n.as_secs()),
+                Err(_) => None,
+            };
+        }
+    }
+}
+
+/// Record the exception thrown by the plugin.
+/// Returns true if the plugin is quarantined.
+pub fn record_plugin_error(plugin_id: &str, message: String, stack: Option<String>) -> bool {
+    match PLUGIN_FAULTS.write() {
+        Ok(mut faults) => {
+            let fault = faults
+                .entry(plugin_id.to_string())
+                .or_insert_with(|| PluginFault::new(plugin_id));
+            fault.record(message, stack, Instant::now());
+            if fault.quarantined {
+                log::error!("Plugin {} is quarantined: {}", plugin_id, fault.last_error);
+            }
+            fault.quarantined
+        }
+        Err(err) => {
+            log::error!("Cannot get lock for plugin faults: {:?}", err);
+            false
+        }
+    }
+}
+
+/// Quarantine the plugin immediately. e.g. the plugin exceeded the execution budget.
+pub fn fault_plugin(plugin_id: &str, message: String) {
+    match PLUGIN_FAULTS.write() {
+        Ok(mut faults) => {
+            let fault = faults
+                .entry(plugin_id.to_string())
+                .or_insert_with(|| PluginFault::new(plugin_id));
+            log::error!("Plugin {} is faulted: {}", plugin_id, message);
+            fault.error_count += 1;
+            fault.last_error = message;
+            fault.last_stack = None;
+            fault.quarantine();
+        }
+        Err(err) => log::error!("Cannot get lock for plugin faults: {:?}", err),
+    }
+}
+
+/// Forget the faults of the plugin. e.g. the plugin was reloaded.
+pub fn reset_plugin_fault(plugin_id: &str) {
+    match PLUGIN_FAULTS.write() {
+        Ok(mut faults) => {
+            faults.remove(plugin_id);
+        }
+        Err(err) => log::error!("Cannot get lock for plugin faults: {:?}", err),
+    }
+}
+
+pub fn get_plugin_faults() -> Vec<PluginFault> {
+    match PLUGIN_FAULTS.read() {
+        Ok(faults) => faults.values().cloned().collect(),
+        Err(err) => {
+            log::error!("Cannot get lock for plugin faults: {:?}", err);
+            vec![]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quarantine() {
+        let mut fault = PluginFault::new("test");
+        let now = Instant::now();
+        for i in 0..MAX_ERRORS {
+            fault.record(format!("error {}", i), None, now);
+        }
+        assert!(!fault.quarantined);
+
+        fault.record("boom".to_string(), Some("stack".to_string()), now);
+        assert!(fault.quarantined);
+        assert_eq!(fault.error_count, MAX_ERRORS as u64 + 1);
+        assert_eq!(fault.last_error, "boom");
+        assert_eq!(fault.last_stack, Some("stack".to_string()));
+    }
+
+    #[test]
+    fn test_old_errors_are_forgotten() {
+        let mut fault = PluginFault::new("test");
+        let now = Instant::now();
+        for _ in 0..MAX_ERRORS {
+            fault.record("old".to_string(), None, now);
+        }
+
+        fault.record(
+            "new".to_string(),
+            None,
+            now + ERROR_WINDOW + Duration::from_secs(1),
+        );
+        assert!(!fault.quarantined);
+        assert_eq!(fault.recent_errors.len(), 1);
+    }
+}
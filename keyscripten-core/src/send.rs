@@ -1,9 +1,11 @@
 use anyhow::anyhow;
+use apple_sys::CoreFoundation::{CFRelease, CFTypeRef};
 use apple_sys::CoreGraphics::{
-    CGEventCreate, CGEventCreateKeyboardEvent, CGEventField_kCGEventSourceUserData, CGEventFlags,
-    CGEventPost, CGEventSetFlags, CGEventSetIntegerValueField, CGEventSetType, CGEventSourceCreate,
-    CGEventSourceRef, CGEventSourceStateID_kCGEventSourceStatePrivate,
-    CGEventTapLocation_kCGHIDEventTap, CGEventType_kCGEventFlagsChanged, CGKeyCode,
+    CGEventCreate, CGEventCreateCopy, CGEventCreateKeyboardEvent,
+    CGEventField_kCGEventSourceUserData, CGEventFlags, CGEventPost, CGEventRef, CGEventSetFlags,
+    CGEventSetIntegerValueField, CGEventSetType, CGEventSourceCreate, CGEventSourceRef,
+    CGEventSourceStateID_kCGEventSourceStatePrivate, CGEventTapLocation_kCGHIDEventTap,
+    CGEventType_kCGEventFlagsChanged, CGKeyCode,
 };
 
 #[link(name = "Cocoa", kind = "framework")]
@@ -55,7 +57,45 @@ pub fn send_flags_changed_event(flags: CGEventFlags) -> anyhow::Result<()> {
         }
         CGEventSetType(event, CGEventType_kCGEventFlagsChanged);
         CGEventSetFlags(event, flags);
+        CGEventSetIntegerValueField(
+            event,
+            CGEventField_kCGEventSourceUserData,
+            USER_DATA_FROM_THIS_APP,
+        );
         CGEventPost(CGEventTapLocation_kCGHIDEventTap, event);
         Ok(())
     }
 }
+
+// Copy the event, and mark it as sent from this application.
+pub fn copy_event(cg_event: CGEventRef) -> anyhow::Result<CGEventRef> {
+    unsafe {
+        let event = CGEventCreateCopy(cg_event);
+        if event.is_null() {
+            return Err(anyhow!("Cannot copy CGEvent"));
+        }
+        CGEventSetIntegerValueField(
+            event,
+            CGEventField_kCGEventSourceUserData,
+            USER_DATA_FROM_THIS_APP,
+        );
+        Ok(event)
+    }
+}
+
+pub fn post_event(cg_event: CGEventRef) -> anyhow::Result<()> {
+    unsafe {
+        CGEventPost(CGEventTapLocation_kCGHIDEventTap, cg_event);
+        Ok(())
+    }
+}
+
+// Release the copy made by `copy_event`. The tests use null events.
+pub fn release_event(cg_event: CGEventRef) {
+    if cg_event.is_null() {
+        return;
+    }
+    unsafe {
+        CFRelease(cg_event as CFTypeRef);
+    }
+}
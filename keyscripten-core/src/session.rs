@@ -0,0 +1,190 @@
+use crate::APP_NAME;
+use anyhow::anyhow;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+lazy_static! {
+    static ref ACTIVE_PLUGIN: RwLock<Option<String>> = RwLock::new(None);
+    // The marker of the current session, and its path. None until the session starts.
+    static ref SESSION: Mutex<Option<(PathBuf, SessionMarker)>> = Mutex::new(None);
+}
+
+thread_local! {
+    // The active plugin of the JS worker on this thread, shared with the worker's supervisor.
+    static WORKER_ACTIVE_PLUGIN: RefCell<Option<Arc<RwLock<Option<String>>>>> =
+        const { RefCell::new(None) };
+}
+
+/// "Session in progress" marker. It's left in the data dir, if the app crashed or was force-quit.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SessionMarker {
+    pub pid: u32,
+    // in epoch seconds
+    pub started_at: u64,
+    // The plugin that overran its budget or missed the deadline last.
+    pub last_plugin: Option<String>,
+}
+
+impl SessionMarker {
+    fn new() -> Self {
+        SessionMarker {
+            pid: std::process::id(),
+            started_at: match SystemTime::now().duration_since(UNIX_EPOCH) {
+                Ok(n) => n.as_secs(),
+                Err(_) => 0,
+            },
+            last_plugin: None,
+        }
+    }
+}
+
+fn marker_path() -> anyhow::Result<PathBuf> {
+    let data_dir = dirs::data_dir().ok_or_else(|| anyhow!("Cannot get data dir"))?;
+    Ok(data_dir.join(APP_NAME).join("session.json"))
+}
+
+fn read_marker(path: &Path) -> Option<SessionMarker> {
+    if !path.exists() {
+        return None;
+    }
+    match fs::read_to_string(path) {
+        Ok(json) => match serde_json::from_str(&json) {
+            Ok(marker) => Some(marker),
+            Err(err) => {
+                // The app may have died while writing it.
+                log::warn!("Cannot parse session marker({:?}): {:?}", path, err);
+                Some(SessionMarker::default())
+            }
+        },
+        Err(err) => {
+            log::warn!("Cannot read session marker({:?}): {:?}", path, err);
+            Some(SessionMarker::default())
+        }
+    }
+}
+
+fn write_marker(path: &Path, marker: &SessionMarker) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, serde_json::to_string(marker)?)?;
+    Ok(())
+}
+
+/// Start the session. Returns the marker of the previous session, if it didn't end normally.
+pub fn start_session() -> anyhow::Result<Option<SessionMarker>> {
+    let path = marker_path()?;
+    let previous = read_marker(&path);
+    if let Some(previous) = &previous {
+        log::warn!("The previous session didn't end normally: {:?}", previous);
+    }
+
+    let marker = SessionMarker::new();
+    write_marker(&path, &marker)?;
+    match SESSION.lock() {
+        Ok(mut session) => *session = Some((path, marker)),
+        Err(err) => log::error!("Cannot get lock for session: {:?}", err),
+    }
+    Ok(previous)
+}
+
+/// Called when the app quits normally. It's safe to call it more than once.
+pub fn end_session() {
+    let session = match SESSION.lock() {
+        Ok(mut session) => session.take(),
+        Err(err) => {
+            log::error!("Cannot get lock for session: {:?}", err);
+            return;
+        }
+    };
+    if let Some((path, _)) = session {
+        if let Err(err) = fs::remove_file(&path) {
+            log::error!("Cannot remove session marker({:?}): {:?}", path, err);
+        }
+    }
+}
+
+/// Called by the driver, when the plugin's callback starts and ends.
+/// It's kept in memory. It runs for every plugin on every key, so it's too often to write.
+pub fn set_active_plugin(plugin_id: Option<String>) {
+    WORKER_ACTIVE_PLUGIN.with(|shared| {
+        if let Some(shared) = shared.borrow().as_ref() {
+            match shared.write() {
+                Ok(mut active_plugin) => active_plugin.clone_from(&plugin_id),
+                Err(err) => log::error!("Cannot get lock for active plugin: {:?}", err),
+            }
+        }
+    });
+    match ACTIVE_PLUGIN.write() {
+        Ok(mut active_plugin) => *active_plugin = plugin_id,
+        Err(err) => log::error!("Cannot get lock for active plugin: {:?}", err),
+    }
+}
+
+/// Write the active plugin to the marker. Called when the plugin overran its budget, or the
+/// worker missed the deadline, since the plugin may be hanging or about to crash the app.
+pub fn persist_active_plugin() {
+    let plugin_id = get_active_plugin();
+    if plugin_id.is_none() {
+        return;
+    }
+    let mut session = match SESSION.lock() {
+        Ok(session) => session,
+        Err(err) => {
+            log::error!("Cannot get lock for session: {:?}", err);
+            return;
+        }
+    };
+    if let Some((path, marker)) = session.as_mut() {
+        if marker.last_plugin != plugin_id {
+            marker.last_plugin = plugin_id;
+            if let Err(err) = write_marker(path, marker) {
+                log::error!("Cannot write session marker({:?}): {:?}", path, err);
+            }
+        }
+    }
+}
+
+/// Called on the JS worker thread. The active plugin on this thread is also set to `shared`, so
+/// that the supervisor knows which plugin hung the worker.
+pub(crate) fn share_active_plugin(shared: Arc<RwLock<Option<String>>>) {
+    WORKER_ACTIVE_PLUGIN.with(|it| *it.borrow_mut() = Some(shared));
+}
+
+pub(crate) fn get_active_plugin() -> Option<String> {
+    match ACTIVE_PLUGIN.read() {
+        Ok(active_plugin) => active_plugin.clone(),
+        Err(err) => {
+            log::error!("Cannot get lock for active plugin: {:?}", err);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_marker() -> anyhow::Result<()> {
+        let temp_dir = TempDir::with_prefix("session")?;
+        let path = temp_dir.path().join("session.json");
+        assert_eq!(read_marker(&path), None);
+
+        let mut marker = SessionMarker::new();
+        marker.last_plugin = Some("broken".to_string());
+        write_marker(&path, &marker)?;
+        assert_eq!(read_marker(&path), Some(marker));
+
+        // half-written marker still means the crash.
+        fs::write(&path, "{\"pid\":")?;
+        assert_eq!(read_marker(&path), Some(SessionMarker::default()));
+        Ok(())
+    }
+}
@@ -0,0 +1,463 @@
+use crate::event::Event;
+use crate::js::JS;
+use crate::js_operation::{report_js_operation_result, JsOperationResult};
+use crate::output::{self, Pending};
+use crate::passthrough::is_passthrough;
+use crate::quarantine::fault_plugin;
+use crate::session;
+use anyhow::anyhow;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Interval of the JS timer ticks.
+const TICK_INTERVAL: Duration = Duration::from_millis(10);
+
+lazy_static! {
+    static ref DEADLINE_MISSES: RwLock<DeadlineMisses> = RwLock::new(DeadlineMisses::default());
+}
+
+pub enum WorkerRequest {
+    Event {
+        event: Event,
+        suppressed: bool,
+        // The tap thread passes the event through after this.
+        deadline: Instant,
+        // The decision, and the events the plugins sent while handling the event.
+        reply: SyncSender<(bool, Pending)>,
+    },
+}
+
+/// Events passed through, because the JS worker didn't answer by the deadline.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DeadlineMisses {
+    pub count: u64,
+    // in epoch seconds
+    pub last_missed_at: Option<u64>,
+    pub last_event_type: Option<String>,
+}
+
+pub fn get_deadline_misses() -> DeadlineMisses {
+    match DEADLINE_MISSES.read() {
+        Ok(misses) => misses.clone(),
+        Err(err) => {
+            log::error!("Cannot get lock for deadline misses: {:?}", err);
+            DeadlineMisses::default()
+        }
+    }
+}
+
+fn record_deadline_miss(event: &Event) {
+    match DEADLINE_MISSES.write() {
+        Ok(mut misses) => {
+            misses.count += 1;
+            misses.last_missed_at = match SystemTime::now().duration_since(UNIX_EPOCH) {
+                Ok(n) => Some(n.as_secs()),
+                Err(_) => None,
+            };
+            misses.last_event_type = Some(event.event_type.clone());
+        }
+        Err(err) => log::error!("Cannot get lock for deadline misses: {:?}", err),
+    }
+}
+
+// The worker is restarted at most MAX_RESTARTS times in RESTART_WINDOW.
+// After that, every event passes through.
+const MAX_RESTARTS: usize = 3;
+const RESTART_WINDOW: Duration = Duration::from_secs(60);
+
+/// The operation reported when the worker died too often, and all plugins are disabled.
+pub const WORKER_STOPPED: &str = "WorkerStopped";
+
+// The worker is hung, if it missed this many deadlines in a row while the same plugin was running.
+// The budget is checked only in the native API calls, so a plugin without them is never aborted.
+const MAX_CONSECUTIVE_MISSES: usize = 5;
+const HUNG_MESSAGE: &str = "The plugin hung the JS engine, and it was unloaded";
+
+type BuildJs = dyn Fn() -> anyhow::Result<JS> + Send + Sync;
+
+/// Handle to the JS worker thread, used from the event tap thread.
+///
+/// It also supervises the worker thread. If the thread dies by a panic or hangs, a new thread is
+/// started with a new `JS` instance. The hung thread can't be stopped, and it's left behind.
+pub struct Worker {
+    tx: Option<Sender<WorkerRequest>>,
+    deadline: Duration,
+    build_js: Arc<BuildJs>,
+    restarts: VecDeque<Instant>,
+    // The plugin running on the worker thread.
+    active_plugin: Arc<RwLock<Option<String>>>,
+    // The plugin running at the last deadline misses, and the number of them in a row.
+    hung_suspect: Option<(String, usize)>,
+    // They are not loaded on the restarted worker.
+    hung_plugins: Vec<String>,
+    // The events handled after the deadline. The events sent for them were dropped.
+    late_events: Arc<AtomicU64>,
+}
+
+impl Worker {
+    /// Spawn the worker thread. `build_js` is called on the worker thread, since `JS` can't be
+    /// moved across threads. It's called again when the worker is restarted.
+    pub fn spawn<F>(build_js: F, deadline: Duration) -> anyhow::Result<Worker>
+    where
+        F: Fn() -> anyhow::Result<JS> + Send + Sync + 'static,
+    {
+        let build_js: Arc<BuildJs> = Arc::new(build_js);
+        let active_plugin = Arc::new(RwLock::new(None));
+        let late_events = Arc::new(AtomicU64::new(0));
+        let (tx, setup_rx) = start(
+            Arc::clone(&build_js),
+            Arc::clone(&active_plugin),
+            vec![],
+            Arc::clone(&late_events),
+        );
+        setup_rx
+            .recv()
+            .map_err(|err| anyhow!("JS worker thread was terminated: {:?}", err))??;
+        Ok(Worker {
+            tx: Some(tx),
+            deadline,
+            build_js,
+            restarts: VecDeque::new(),
+            active_plugin,
+            hung_suspect: None,
+            hung_plugins: vec![],
+            late_events,
+        })
+    }
+
+    // Called when the worker thread died or hung.
+    // This doesn't wait for the new JS instance. The events are queued until it's ready.
+    fn restart(&mut self) {
+        let now = Instant::now();
+        self.restarts
+            .retain(|at| now.duration_since(*at) <= RESTART_WINDOW);
+        if self.restarts.len() >= MAX_RESTARTS {
+            log::error!("JS worker died too often. All events pass through.");
+            self.tx = None;
+            report_js_operation_result(JsOperationResult::new(
+                WORKER_STOPPED,
+                Err(anyhow!(
+                    "The JS engine died {} times in {} seconds. All plugins are disabled until KeyScripten restarts.",
+                    MAX_RESTARTS + 1,
+                    RESTART_WINDOW.as_secs()
+                )),
+            ));
+            return;
+        }
+        self.restarts.push_back(now);
+
+        log::warn!("Restarting JS worker");
+        // A new one, since the hung thread may still set the old one.
+        self.active_plugin = Arc::new(RwLock::new(None));
+        self.hung_suspect = None;
+        let (tx, _setup_rx) = start(
+            Arc::clone(&self.build_js),
+            Arc::clone(&self.active_plugin),
+            self.hung_plugins.clone(),
+            Arc::clone(&self.late_events),
+        );
+        self.tx = Some(tx);
+    }
+
+    // Called when the worker missed the deadline.
+    // If the same plugin kept running for MAX_CONSECUTIVE_MISSES, it's faulted and the worker is
+    // restarted without it.
+    fn check_hung(&mut self) {
+        let active_plugin = match self.active_plugin.read() {
+            Ok(active_plugin) => active_plugin.clone(),
+            Err(err) => {
+                log::error!("Cannot get lock for active plugin: {:?}", err);
+                None
+            }
+        };
+        // e.g. the worker is loading the plugins.
+        let Some(plugin_id) = active_plugin else {
+            self.hung_suspect = None;
+            return;
+        };
+        let misses = match &self.hung_suspect {
+            Some((suspect, misses)) if *suspect == plugin_id => misses + 1,
+            _ => 1,
+        };
+        if misses < MAX_CONSECUTIVE_MISSES {
+            self.hung_suspect = Some((plugin_id, misses));
+            return;
+        }
+
+        log::error!("JS worker is hung by {}", plugin_id);
+        fault_plugin(&plugin_id, HUNG_MESSAGE.to_string());
+        self.hung_plugins.push(plugin_id);
+        self.restart();
+    }
+
+    /// Ask the worker whether the event is passed to the application.
+    /// If the worker doesn't answer by the deadline, the event is passed through.
+    pub fn send_event(&mut self, event: &Event, suppressed: bool) -> (bool, Pending) {
+        let Some(tx) = &self.tx else {
+            return (true, Pending::default());
+        };
+
+        let (reply_tx, reply_rx) = mpsc::sync_channel::<(bool, Pending)>(1);
+        let deadline = Instant::now() + self.deadline;
+        let request = WorkerRequest::Event {
+            event: event.clone(),
+            suppressed,
+            deadline,
+            reply: reply_tx,
+        };
+        if let Err(err) = tx.send(request) {
+            log::error!("Cannot send the event to the JS worker: {:?}", err);
+            self.restart();
+            return (true, Pending::default());
+        }
+
+        match reply_rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(reply) => {
+                self.hung_suspect = None;
+                reply
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                log::warn!(
+                    "JS worker missed the deadline({:?}) for {}",
+                    self.deadline,
+                    event.event_type
+                );
+                record_deadline_miss(event);
+                // The plugin may be hanging. Off the tap thread, since it writes a file.
+                thread::spawn(session::persist_active_plugin);
+                self.check_hung();
+                (true, Pending::default())
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                log::error!("JS worker died while handling {}", event.event_type);
+                self.restart();
+                (true, Pending::default())
+            }
+        }
+    }
+}
+
+// Start the worker thread. The setup result is sent to the returned receiver.
+// The plugin running on the thread is set to `active_plugin`.
+fn start(
+    build_js: Arc<BuildJs>,
+    active_plugin: Arc<RwLock<Option<String>>>,
+    hung_plugins: Vec<String>,
+    late_events: Arc<AtomicU64>,
+) -> (Sender<WorkerRequest>, Receiver<anyhow::Result<()>>) {
+    let (tx, rx) = mpsc::channel::<WorkerRequest>();
+    let (setup_tx, setup_rx) = mpsc::channel::<anyhow::Result<()>>();
+
+    thread::spawn(move || {
+        log::debug!("Starting JS worker thread: {:?}", thread::current().id());
+        session::share_active_plugin(active_plugin);
+        let mut js = match build_js() {
+            Ok(js) => js,
+            Err(err) => {
+                log::error!("Cannot build JS instance: {:?}", err);
+                let _ = setup_tx.send(Err(err));
+                return;
+            }
+        };
+        for plugin_id in hung_plugins {
+            if let Err(err) = js.unload_plugin(plugin_id.clone()) {
+                log::error!("Cannot unload the hung plugin {}: {:?}", plugin_id, err);
+            }
+            // Loading the plugin forgot its fault.
+            fault_plugin(&plugin_id, HUNG_MESSAGE.to_string());
+        }
+        let _ = setup_tx.send(Ok(()));
+        run(js, rx, late_events);
+    });
+
+    (tx, setup_rx)
+}
+
+fn run(mut js: JS, rx: Receiver<WorkerRequest>, late_events: Arc<AtomicU64>) {
+    let mut next_tick = Instant::now() + TICK_INTERVAL;
+    loop {
+        let timeout = next_tick.saturating_duration_since(Instant::now());
+        let request = rx.recv_timeout(timeout);
+
+        // The operations from the UI are applied within a tick, even if no key is pressed.
+        js.process_js_operations();
+
+        match request {
+            Ok(WorkerRequest::Event {
+                event,
+                suppressed,
+                deadline,
+                reply,
+            }) => {
+                // The worker was busy, and the tap thread passed the event through already.
+                if Instant::now() >= deadline {
+                    log::debug!("Skipping the expired {}", event.event_type);
+                } else {
+                    if !handle_event(&mut js, &event, suppressed, reply) {
+                        late_events.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                log::warn!("Event tap was disconnected. Stopping JS worker.");
+                return;
+            }
+        }
+
+        // Timers fire even while the user is typing fast.
+        // The plugins don't run in passthrough mode.
+        if Instant::now() >= next_tick && !is_passthrough() {
+            if let Err(err) = js.tick() {
+                log::error!("Cannot run JS timers: {:?}", err);
+            }
+            output::flush(None);
+            next_tick = Instant::now() + TICK_INTERVAL;
+        }
+    }
+}
+
+// Returns false if the tap thread didn't wait for the reply.
+fn handle_event(
+    js: &mut JS,
+    event: &Event,
+    suppressed: bool,
+    reply: SyncSender<(bool, Pending)>,
+) -> bool {
+    let pass = match js.send_event(event, suppressed) {
+        Ok(b) => b,
+        Err(err) => {
+            log::error!("Cannot call JS callback: {:?}", err);
+            true
+        }
+    };
+    let pending = output::take_pending();
+    // The tap thread gave up already, and the original event was passed as is.
+    // The events sent for it are dropped, since they would be out of order.
+    if reply.send((pass, pending)).is_err() {
+        log::warn!("Dropped the events sent for the late {}", event.event_type);
+        return false;
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quarantine::get_plugin_faults;
+
+    #[test]
+    fn test_late_events_are_dropped() -> anyhow::Result<()> {
+        let mut worker = Worker::spawn(
+            || {
+                let mut js = JS::new(None, None, None)?;
+                js.load_in_new_realm(
+                    "slow.js",
+                    r#"
+                    registerPlugin("slow", "Slow", "", function (event) {
+                        if (event.keycode === 17) {
+                            const until = Date.now() + 50;
+                            while (Date.now() < until) {}
+                            sendKeyboardEvent(0, 0, true);
+                        }
+                        return false;
+                    }, []);
+                    "#
+                    .to_string(),
+                )?;
+                Ok(js)
+            },
+            Duration::from_millis(10),
+        )?;
+        let event = |keycode| Event {
+            event_type: "keyDown".to_string(),
+            keycode,
+            flags: 0,
+            autorepeat: false,
+            timestamp: 0,
+        };
+
+        // The second event expires while the worker handles the first one.
+        let (pass, _) = worker.send_event(&event(17), false);
+        assert!(pass);
+        let (pass, _) = worker.send_event(&event(17), false);
+        assert!(pass);
+
+        // Wait for the worker to finish the first one.
+        worker.deadline = Duration::from_secs(10);
+        let (pass, _) = worker.send_event(&event(18), false);
+        assert!(!pass);
+
+        // The first one was handled late, and the second one was skipped.
+        assert_eq!(worker.late_events.load(Ordering::Relaxed), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_hung_worker_is_restarted() -> anyhow::Result<()> {
+        let mut worker = Worker::spawn(
+            || {
+                let mut js = JS::new(None, None, None)?;
+                // No native API is called, so the budget is never checked.
+                js.load_in_new_realm(
+                    "hang.js",
+                    r#"
+                    function f() {
+                        let n = 0;
+                        for (let i = 0; i < 999000; i++) { n += i; }
+                        return n;
+                    }
+                    registerPlugin("hang", "Hang", "", function () {
+                        while (true) { f(); }
+                    }, []);
+                    "#
+                    .to_string(),
+                )?;
+                js.load_in_new_realm(
+                    "drop.js",
+                    r#"
+                    registerPlugin("drop", "Drop", "", function () { return false; }, []);
+                    "#
+                    .to_string(),
+                )?;
+                Ok(js)
+            },
+            Duration::from_millis(10),
+        )?;
+        let event = Event {
+            event_type: "keyDown".to_string(),
+            keycode: 17,
+            flags: 0,
+            autorepeat: false,
+            timestamp: 0,
+        };
+
+        for _ in 0..MAX_CONSECUTIVE_MISSES {
+            let (pass, _) = worker.send_event(&event, false);
+            assert!(pass);
+        }
+        let fault = get_plugin_faults()
+            .into_iter()
+            .find(|fault| fault.plugin_id == "hang");
+        assert!(fault.is_some_and(|fault| fault.quarantined));
+
+        // The new worker answers without the hung plugin, once it's ready.
+        let mut dropped = false;
+        for _ in 0..500 {
+            let (pass, _) = worker.send_event(&event, false);
+            if !pass {
+                dropped = true;
+                break;
+            }
+        }
+        assert!(dropped);
+        assert_eq!(worker.hung_plugins, vec!["hang".to_string()]);
+        Ok(())
+    }
+}
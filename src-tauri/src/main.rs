@@ -1,29 +1,44 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::path::PathBuf;
 use std::{fs, thread};
 
 use std::str::FromStr;
-use std::sync::{mpsc, Arc, RwLock};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::time::Duration;
 
 use anyhow::anyhow;
 
 use chrono::{Local, SecondsFormat};
-use keyscripten_core::app_config::{AppConfig, PluginConfig};
+use keyscripten_core::app_config::{AppConfig, ConfigBackup, ConfigParseError, PluginConfig};
+use keyscripten_core::config_schema::{self, ConfigItem};
+use keyscripten_core::crash_report;
 use keyscripten_core::event::Event;
+use keyscripten_core::file_security::{self, InsecureFile};
 use keyscripten_core::grab::{grab_run, grab_setup};
-use keyscripten_core::js::{ConfigSchema, ConfigSchemaList, JS};
+use keyscripten_core::js::{ConfigSchema, ConfigSchemaList, EvalResult, BUNDLED_PLUGIN_IDS, JS};
 use keyscripten_core::js_console::TimedLogMessage;
-use keyscripten_core::js_operation::JsOperation;
+use keyscripten_core::js_operation::{self, JsOperation, JsOperationResult};
+use keyscripten_core::kill_switch::KillSwitch;
+use keyscripten_core::passthrough::{self, PassthroughState, PauseHotKey};
 use keyscripten_core::plugin::Plugins;
+use keyscripten_core::plugin_manifest::PluginManifest;
+use keyscripten_core::quarantine::PluginFault;
+use keyscripten_core::session::{self, SessionMarker};
+use keyscripten_core::worker::{DeadlineMisses, Worker, WORKER_STOPPED};
 use lazy_static::lazy_static;
 use log::{LevelFilter, Record};
 use tauri::api::dialog;
+use tauri::api::notification::Notification;
 use tauri::{
-    CustomMenuItem, Manager, SystemTray, SystemTrayEvent, SystemTrayMenu, SystemTrayMenuItem,
-    WindowBuilder, Wry,
+    AppHandle, CustomMenuItem, Icon, Manager, State, SystemTray, SystemTrayEvent, SystemTrayMenu,
+    SystemTrayMenuItem, WindowBuilder, Wry,
 };
 
 const APP_NAME: &str = "keyscripten";
+// Prefix of the tray menu item ids for the plugins.
+const PLUGIN_MENU_PREFIX: &str = "plugin:";
+// The worker answers the queries between the events.
+const JS_QUERY_TIMEOUT: Duration = Duration::from_secs(5);
 
 static mut LOG_LEVEL: RwLock<LevelFilter> = RwLock::new(LevelFilter::Info);
 
@@ -34,17 +49,63 @@ lazy_static! {
     static ref LOG_BUFFER: RwLock<VecDeque<String>> = RwLock::new(VecDeque::new());
 }
 
-fn build_js<'a>() -> Result<JS, String> {
-    let plugins = Plugins::new().map_err(|err| format!("Plugins::new: {:?}", err))?;
-    let mut js = JS::new(None, None, Some(plugins)).map_err(|err| format!("{:?}", err))?;
-    js.load_user_scripts()
-        .map_err(|err| format!("load_user_scripts: {:?}", err))?;
-    Ok(js)
+// Sends the operations to the JS worker. It's replaced when the worker is restarted.
+struct JsOperationSender(Arc<Mutex<mpsc::Sender<JsOperation>>>);
+
+// Safe mode doesn't load the plugins.
+struct SafeMode(bool);
+
+impl JsOperationSender {
+    fn send(&self, js_operation: JsOperation) -> Result<(), String> {
+        let tx = self
+            .0
+            .lock()
+            .map_err(|err| format!("Cannot get lock for js-operation sender: {:?}", err))?;
+        tx.send(js_operation)
+            .map_err(|err| format!("Cannot send js-operation: {:?}", err))
+    }
+
+    // Ask the worker, and wait for the answer.
+    fn query<T>(
+        &self,
+        build_operation: impl FnOnce(mpsc::Sender<T>) -> JsOperation,
+    ) -> Result<T, String> {
+        let (reply_tx, reply_rx) = mpsc::channel::<T>();
+        self.send(build_operation(reply_tx))?;
+        reply_rx
+            .recv_timeout(JS_QUERY_TIMEOUT)
+            .map_err(|err| format!("JS worker didn't answer: {:?}", err))
+    }
+}
+
+fn send_js_operation(app: &AppHandle, js_operation: JsOperation) {
+    if let Err(err) = app.state::<JsOperationSender>().send(js_operation) {
+        log::error!("{}", err);
+    }
+}
+
+fn query_config_schema(sender: &JsOperationSender) -> Result<ConfigSchemaList, String> {
+    sender
+        .query(|reply| JsOperation::GetConfigSchema { reply })?
+        .map_err(|err| format!("get_config_schema: {:?}", err))
 }
 
-fn get_filename_by_plugin_id(plugin_id: String) -> Result<String, String> {
-    let js = build_js().map_err(|err| format!("Cannot build js: {:?}", err))?;
-    if let Some(filename) = js.get_filename_by_plugin_id(&plugin_id) {
+// Resolve the file by the manifests first. They are readable even when the plugin isn't loaded,
+// e.g. in safe mode or after a failed load. The scripts without the manifest are known only by JS.
+fn get_filename_by_plugin_id(
+    sender: &JsOperationSender,
+    plugin_id: String,
+) -> Result<String, String> {
+    let plugins = Plugins::new().map_err(|err| format!("Cannot list plugin: {:?}", err))?;
+    let filename = match plugins.find_filename_by_manifest(&plugin_id) {
+        Ok(Some(filename)) => Some(filename),
+        Ok(None) => sender.query(|reply| JsOperation::GetFilenameByPluginId {
+            plugin_id: plugin_id.clone(),
+            reply,
+        })?,
+        Err(err) => return Err(format!("Cannot list plugin manifests: {:?}", err)),
+    };
+    if let Some(filename) = filename {
         Ok(filename)
     } else {
         log::error!("There's no plugin file found for {}", plugin_id);
@@ -53,16 +114,16 @@ fn get_filename_by_plugin_id(plugin_id: String) -> Result<String, String> {
 }
 
 #[tauri::command]
-fn get_config_schema() -> Result<ConfigSchemaList, String> {
-    let mut js = build_js()?;
-    js.get_config_schema()
-        .map_err(|err| format!("get_config_schema: {:?}", err))
+fn get_config_schema(sender: State<JsOperationSender>) -> Result<ConfigSchemaList, String> {
+    query_config_schema(&sender)
 }
 
 #[tauri::command]
-fn get_config_schema_for_plugin(plugin_id: String) -> Result<ConfigSchema, String> {
-    let mut js = build_js()?;
-    let schema_list = js.get_config_schema().map_err(|err| format!("{:?}", err))?;
+fn get_config_schema_for_plugin(
+    sender: State<JsOperationSender>,
+    plugin_id: String,
+) -> Result<ConfigSchema, String> {
+    let schema_list = query_config_schema(&sender)?;
     for plugin in schema_list.plugins {
         if plugin.id == plugin_id {
             return Ok(plugin);
@@ -80,7 +141,113 @@ fn load_config() -> Result<AppConfig, String> {
 }
 
 #[tauri::command]
-fn save_config_for_plugin(plugin_id: String, plugin_config: PluginConfig) -> Result<(), String> {
+fn get_config_parse_error() -> Result<Option<ConfigParseError>, String> {
+    Ok(AppConfig::parse_error())
+}
+
+#[tauri::command]
+fn list_config_backups() -> Result<Vec<ConfigBackup>, String> {
+    Ok(AppConfig::list_backups())
+}
+
+#[tauri::command]
+fn restore_config_backup(sender: State<JsOperationSender>, index: usize) -> Result<(), String> {
+    log::info!("tauri::command: restore_config_backup: {}", index);
+
+    AppConfig::restore_backup(index).map_err(|err| format!("Cannot restore backup: {:#}", err))?;
+    sender.send(JsOperation::ReloadConfig)
+}
+
+// The installed plugins, read from the plugins directory. The plugins may fail to load, or may not
+// be loaded at all.
+fn known_plugin_ids(safe_mode: &SafeMode) -> Result<HashSet<String>, String> {
+    if safe_mode.0 {
+        return Err(
+            "The settings of the deleted plugins can't be removed in safe mode.".to_string(),
+        );
+    }
+    let plugins = Plugins::new().map_err(|err| format!("Cannot list plugin: {:?}", err))?;
+    let mut known_ids = plugins
+        .installed_plugin_ids()
+        .map_err(|err| format!("Cannot list plugins: {:?}", err))?;
+    known_ids.extend(BUNDLED_PLUGIN_IDS.iter().map(|id| id.to_string()));
+    Ok(known_ids)
+}
+
+// The deleted plugins, which still have the settings.
+#[tauri::command]
+fn list_unknown_plugin_configs(safe_mode: State<SafeMode>) -> Result<Vec<String>, String> {
+    let known_ids = known_plugin_ids(&safe_mode)?;
+    let config = AppConfig::load()
+        .map_err(|err| format!("An error occurred while loading configuration: {:?}", err))?;
+    Ok(config.unknown_plugins(&known_ids))
+}
+
+// Remove the settings of the deleted plugins, which the user confirmed.
+#[tauri::command]
+fn remove_unknown_plugin_configs(
+    safe_mode: State<SafeMode>,
+    plugin_ids: Vec<String>,
+) -> Result<Vec<String>, String> {
+    log::info!(
+        "tauri::command: remove_unknown_plugin_configs: {:?}",
+        plugin_ids
+    );
+
+    let known_ids = known_plugin_ids(&safe_mode)?;
+    let mut config = AppConfig::load()
+        .map_err(|err| format!("An error occurred while loading configuration: {:?}", err))?;
+    let removed = config.remove_unknown_plugins(&plugin_ids, &known_ids);
+    if !removed.is_empty() {
+        config
+            .save()
+            .map_err(|err| format!("Cannot save configuration: {:#}", err))?;
+    }
+    Ok(removed)
+}
+
+// The schema registered by the running plugin. The manifest has it, even if the plugin isn't running,
+// e.g. in safe mode, or after it failed or was quarantined.
+fn find_config_schema(
+    sender: &JsOperationSender,
+    plugin_id: &str,
+) -> Result<Vec<ConfigItem>, String> {
+    match query_config_schema(sender) {
+        Ok(schemas) => {
+            if let Some(schema) = schemas
+                .plugins
+                .into_iter()
+                .find(|schema| schema.id == plugin_id)
+            {
+                return Ok(schema.config);
+            }
+        }
+        Err(err) => log::warn!("Cannot get the schema of {}: {}", plugin_id, err),
+    }
+    let plugins = Plugins::new().map_err(|err| format!("Cannot list plugin: {:?}", err))?;
+    plugins
+        .list_manifests()
+        .map_err(|err| format!("Cannot list plugin manifests: {:?}", err))?
+        .into_iter()
+        .find(|manifest| manifest.id == plugin_id)
+        .map(|manifest| manifest.config_schema)
+        .ok_or_else(|| format!("Unknown plugin: {}", plugin_id))
+}
+
+#[tauri::command]
+fn save_config_for_plugin(
+    sender: State<JsOperationSender>,
+    plugin_id: String,
+    mut plugin_config: PluginConfig,
+) -> Result<(), String> {
+    let schema = find_config_schema(&sender, &plugin_id)?;
+    if let Some(values) = &plugin_config.config {
+        plugin_config.config = Some(
+            config_schema::validate_config(&schema, values)
+                .map_err(|err| format!("Invalid configuration for {}: {:#}", plugin_id, err))?,
+        );
+    }
+
     let mut config = AppConfig::load()
         .map_err(|err| format!("An error occurred while loading configuration: {:?}", err))?;
     config
@@ -141,14 +308,25 @@ fn list_plugins() -> Result<Vec<String>, String> {
 }
 
 #[tauri::command]
-fn get_plugin_filename(plugin_id: String) -> Result<String, String> {
-    let filename = get_filename_by_plugin_id(plugin_id)?;
+fn list_plugin_manifests() -> Result<Vec<PluginManifest>, String> {
+    let plugins = Plugins::new().map_err(|err| format!("Cannot list plugin: {:?}", err))?;
+    plugins
+        .list_manifests()
+        .map_err(|err| format!("Cannot list plugin manifests: {:?}", err))
+}
+
+#[tauri::command]
+fn get_plugin_filename(
+    sender: State<JsOperationSender>,
+    plugin_id: String,
+) -> Result<String, String> {
+    let filename = get_filename_by_plugin_id(&sender, plugin_id)?;
     Ok(filename)
 }
 
 #[tauri::command]
-fn read_plugin_code(plugin_id: String) -> Result<String, String> {
-    let filename = get_filename_by_plugin_id(plugin_id)?;
+fn read_plugin_code(sender: State<JsOperationSender>, plugin_id: String) -> Result<String, String> {
+    let filename = get_filename_by_plugin_id(&sender, plugin_id)?;
 
     let plugins = Plugins::new().map_err(|err| format!("Cannot read plugin: {:?}", err))?;
     let plugin_snippet = plugins
@@ -158,10 +336,14 @@ fn read_plugin_code(plugin_id: String) -> Result<String, String> {
 }
 
 #[tauri::command]
-fn write_plugin_code(plugin_id: String, code: String) -> Result<(), String> {
+fn write_plugin_code(
+    sender: State<JsOperationSender>,
+    plugin_id: String,
+    code: String,
+) -> Result<(), String> {
     log::info!("tauri::command: write_plugin_code: {}", plugin_id);
 
-    let filename = get_filename_by_plugin_id(plugin_id)?;
+    let filename = get_filename_by_plugin_id(&sender, plugin_id)?;
     let plugins = Plugins::new().map_err(|err| format!("Cannot write plugin: {:?}", err))?;
     plugins
         .write(filename, code)
@@ -169,10 +351,10 @@ fn write_plugin_code(plugin_id: String, code: String) -> Result<(), String> {
 }
 
 #[tauri::command]
-fn delete_plugin(plugin_id: String) -> Result<(), String> {
+fn delete_plugin(sender: State<JsOperationSender>, plugin_id: String) -> Result<(), String> {
     log::info!("tauri::command: delete_plugin: {}", plugin_id);
 
-    let filename = get_filename_by_plugin_id(plugin_id)?;
+    let filename = get_filename_by_plugin_id(&sender, plugin_id)?;
     let plugins =
         Plugins::new().map_err(|err| format!("Cannot construct Plugins instance: {:?}", err))?;
     plugins
@@ -180,6 +362,20 @@ fn delete_plugin(plugin_id: String) -> Result<(), String> {
         .map_err(|err| format!("Cannot delete plugin: {:?}", err))
 }
 
+#[tauri::command]
+fn eval_js(
+    sender: State<JsOperationSender>,
+    code: String,
+    plugin_id: Option<String>,
+) -> Result<EvalResult, String> {
+    log::info!("tauri::command: eval_js: {:?}", plugin_id);
+    sender.query(|reply| JsOperation::Eval {
+        code,
+        plugin_id,
+        reply,
+    })
+}
+
 #[tauri::command]
 fn read_logs() -> Result<Vec<String>, String> {
     log::debug!("tauri::command: read_logs");
@@ -198,6 +394,224 @@ fn read_console_logs() -> Result<Vec<TimedLogMessage>, String> {
     Ok(buffer)
 }
 
+#[tauri::command]
+fn get_plugin_faults() -> Result<Vec<PluginFault>, String> {
+    log::debug!("tauri::command: get_plugin_faults");
+
+    Ok(keyscripten_core::quarantine::get_plugin_faults())
+}
+
+#[tauri::command]
+fn get_insecure_files() -> Result<Vec<InsecureFile>, String> {
+    log::debug!("tauri::command: get_insecure_files");
+
+    Ok(file_security::get_insecure_files())
+}
+
+#[tauri::command]
+fn fix_insecure_files(sender: State<JsOperationSender>) -> Result<(), String> {
+    log::info!("tauri::command: fix_insecure_files");
+
+    let errors = file_security::get_insecure_files()
+        .iter()
+        .filter_map(|insecure| file_security::fix_permissions(&insecure.path).err())
+        .map(|err| format!("{:#}", err))
+        .collect::<Vec<_>>();
+    // Load the plugins refused so far.
+    sender.send(JsOperation::ReloadPlugins)?;
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join("\n"))
+    }
+}
+
+#[tauri::command]
+fn get_deadline_misses() -> Result<DeadlineMisses, String> {
+    log::debug!("tauri::command: get_deadline_misses");
+
+    Ok(keyscripten_core::worker::get_deadline_misses())
+}
+
+#[tauri::command]
+fn update_remember_pause_state(remember: bool) -> Result<(), String> {
+    let mut config = AppConfig::load()
+        .map_err(|err| format!("An error occurred while loading configuration: {:?}", err))?;
+    config.remember_pause_state = Some(remember);
+    config.paused = if remember {
+        Some(passthrough::get_state().paused)
+    } else {
+        None
+    };
+    config.save().map_err(|err| format!("{:?}", err))
+}
+
+fn save_pause_state(paused: bool) -> anyhow::Result<()> {
+    let mut config = AppConfig::load()?;
+    if config.remember_pause_state.unwrap_or(false) {
+        config.paused = Some(paused);
+        config.save()?;
+    }
+    Ok(())
+}
+
+fn pause_menu_title(state: PassthroughState) -> &'static str {
+    if state.is_passthrough() {
+        "Resume KeyScripten"
+    } else {
+        "Pause KeyScripten"
+    }
+}
+
+fn plugin_menu_items(app: &AppHandle) -> Result<Vec<CustomMenuItem>, String> {
+    let schema_list = query_config_schema(&app.state::<JsOperationSender>())?;
+    let config = AppConfig::load()
+        .map_err(|err| format!("An error occurred while loading configuration: {:?}", err))?;
+    let plugin_configs = config.plugins.unwrap_or_default();
+
+    Ok(schema_list
+        .plugins
+        .into_iter()
+        .map(|plugin| {
+            let enabled = plugin_configs
+                .get(&plugin.id)
+                .map(|plugin_config| plugin_config.enabled)
+                .unwrap_or(true);
+            let item =
+                CustomMenuItem::new(format!("{}{}", PLUGIN_MENU_PREFIX, plugin.id), plugin.name);
+            if enabled {
+                item.selected()
+            } else {
+                item
+            }
+        })
+        .collect())
+}
+
+// The plugin items are added after the worker loaded the plugins.
+fn build_tray_menu(app: Option<&AppHandle>) -> SystemTrayMenu {
+    let configuration = CustomMenuItem::new("configuration".to_string(), "Configuration");
+    let pause = CustomMenuItem::new(
+        "pause".to_string(),
+        pause_menu_title(passthrough::get_state()),
+    );
+    let mut tray_menu = SystemTrayMenu::new()
+        .add_item(configuration)
+        .add_item(pause)
+        .add_native_item(SystemTrayMenuItem::Separator);
+
+    match app.map(plugin_menu_items) {
+        Some(Ok(items)) => {
+            for item in items {
+                tray_menu = tray_menu.add_item(item);
+            }
+            tray_menu = tray_menu.add_native_item(SystemTrayMenuItem::Separator);
+        }
+        Some(Err(err)) => log::error!("Cannot list plugins for tray menu: {:?}", err),
+        None => {}
+    }
+
+    let quit = CustomMenuItem::new("quit".to_string(), "Quit");
+    tray_menu.add_item(quit)
+}
+
+// The plugin list may be changed.
+fn refresh_tray_menu(app: &AppHandle) {
+    let app = app.clone();
+    thread::spawn(move || {
+        if let Err(err) = app.tray_handle().set_menu(build_tray_menu(Some(&app))) {
+            log::error!("Cannot update tray menu: {:?}", err);
+        }
+    });
+}
+
+fn toggle_plugin(app: &AppHandle, plugin_id: &str) -> Result<(), String> {
+    let mut config = AppConfig::load()
+        .map_err(|err| format!("An error occurred while loading configuration: {:?}", err))?;
+    let plugin_config = config
+        .plugins
+        .get_or_insert(HashMap::new())
+        .entry(plugin_id.to_string())
+        .or_default();
+    plugin_config.enabled = !plugin_config.enabled;
+    let enabled = plugin_config.enabled;
+    config
+        .save()
+        .map_err(|err| format!("Cannot save configuration for {}: {:?}", plugin_id, err))?;
+
+    send_js_operation(
+        app,
+        JsOperation::SetPluginEnabled {
+            plugin_id: plugin_id.to_string(),
+            enabled,
+        },
+    );
+    app.tray_handle()
+        .get_item(&format!("{}{}", PLUGIN_MENU_PREFIX, plugin_id))
+        .set_selected(enabled)
+        .map_err(|err| format!("Cannot update tray menu: {:?}", err))
+}
+
+// Show the passthrough mode in the system tray.
+fn update_passthrough_indicator(app: &AppHandle, state: PassthroughState) {
+    let tray = app.tray_handle();
+    let icon = if state.is_passthrough() {
+        include_bytes!("../icons/tray-passthrough.png").to_vec()
+    } else {
+        include_bytes!("../icons/icon.png").to_vec()
+    };
+    if let Err(err) = tray.set_icon(Icon::Raw(icon)) {
+        log::error!("Cannot change tray icon: {:?}", err);
+    }
+    if let Err(err) = tray.get_item("pause").set_title(pause_menu_title(state)) {
+        log::error!("Cannot update tray menu: {:?}", err);
+    }
+
+    if state.killed {
+        if let Err(err) = Notification::new(&app.config().tauri.bundle.identifier)
+            .title("KeyScripten")
+            .body("Kill switch was pressed. The plugins are stopped until you resume KeyScripten.")
+            .show()
+        {
+            log::error!("Cannot show notification: {:?}", err);
+        }
+    }
+}
+
+// The events pass through from now on. The user must know why the plugins don't work.
+fn notify_worker_stopped(app: &AppHandle, result: &JsOperationResult) {
+    let message = result.error.as_deref().unwrap_or("The JS engine stopped.");
+    if let Err(err) = Notification::new(&app.config().tauri.bundle.identifier)
+        .title("KeyScripten")
+        .body(message)
+        .show()
+    {
+        log::error!("Cannot show notification: {:?}", err);
+    }
+}
+
+fn safe_mode_message(previous_session: &Option<SessionMarker>) -> String {
+    let reason = match previous_session {
+        Some(SessionMarker {
+            last_plugin: Some(plugin_id),
+            ..
+        }) => format!(
+            "The last session didn't end normally. The last plugin that overran its time budget was `{}`.",
+            plugin_id
+        ),
+        Some(_) => "The last session didn't end normally.".to_string(),
+        None => "--safe-mode was passed.".to_string(),
+    };
+    let plugins_dir = match Plugins::new() {
+        Ok(plugins) => plugins.plugins_dir().display().to_string(),
+        Err(_) => "the plugins directory".to_string(),
+    };
+    format!(
+        "KeyScripten started in safe mode. {}\nThe plugins are not loaded. To fix the plugin, edit or delete it in {}, then restart KeyScripten to load the plugins again.",
+        reason, plugins_dir
+    )
+}
+
 fn set_log_level_by_config(app_config: &AppConfig) {
     let level_filter = match LevelFilter::from_str(app_config.log_level.as_str()) {
         Ok(level) => level,
@@ -269,61 +683,149 @@ fn logger() -> anyhow::Result<()> {
 
 fn main() -> anyhow::Result<()> {
     logger()?;
+    crash_report::install_panic_hook();
 
     let app_config = AppConfig::load()?;
     set_log_level_by_config(&app_config);
 
-    let (js_operation_tx, js_operation_rx) = mpsc::channel::<JsOperation>();
+    let previous_session = match session::start_session() {
+        Ok(previous_session) => previous_session,
+        Err(err) => {
+            log::error!("Cannot start session: {:?}", err);
+            None
+        }
+    };
+    let safe_mode = std::env::args().any(|arg| arg == "--safe-mode") || previous_session.is_some();
+    if safe_mode {
+        log::warn!("Starting in safe mode");
+    }
+
+    // The worker replaces it with the channel to its JS instance.
+    let (js_operation_tx, _) = mpsc::channel::<JsOperation>();
+    let js_operation_tx = Arc::new(Mutex::new(js_operation_tx));
+    let worker_js_operation_tx = Arc::clone(&js_operation_tx);
     let (setup_tx, setup_rx) = mpsc::channel::<anyhow::Result<()>>();
+    let event_deadline = app_config.event_deadline();
+    let kill_switch = match KillSwitch::new(app_config.panic_chord.as_deref()) {
+        Ok(kill_switch) => kill_switch,
+        Err(err) => {
+            log::error!(
+                "Invalid panic chord({:?}): {:?}",
+                app_config.panic_chord,
+                err
+            );
+            KillSwitch::new(None)?
+        }
+    };
+    let pause_hotkey = match &app_config.pause_hotkey {
+        Some(hotkey) => match PauseHotKey::from_str(hotkey) {
+            Ok(pause_hotkey) => Some(pause_hotkey),
+            Err(err) => {
+                log::error!("Invalid pause hotkey({:?}): {:?}", hotkey, err);
+                None
+            }
+        },
+        None => None,
+    };
+    let (passthrough_tx, passthrough_rx) = mpsc::channel::<PassthroughState>();
+    passthrough::set_listener(passthrough_tx);
+    let (js_operation_result_tx, js_operation_result_rx) = mpsc::channel::<JsOperationResult>();
+    js_operation::set_result_listener(js_operation_result_tx);
+    if app_config.remember_pause_state.unwrap_or(false) && app_config.paused.unwrap_or(false) {
+        passthrough::set_paused(true);
+    }
 
     thread::spawn(move || {
         log::debug!("Starting handler thread: {:?}", thread::current().id());
-        let plugins = Plugins::new().expect("Cannot load plugins");
-        let mut js = JS::new(
-            Some(js_operation_rx),
-            Some(Arc::clone(&VEC_DEQUE)),
-            Some(plugins),
+        let result = Worker::spawn(
+            move || {
+                // Called again when the worker is restarted. The new instance gets a new channel.
+                let (js_operation_tx, js_operation_rx) = mpsc::channel::<JsOperation>();
+                match worker_js_operation_tx.lock() {
+                    Ok(mut tx) => *tx = js_operation_tx,
+                    Err(err) => log::error!("Cannot get lock for js-operation sender: {:?}", err),
+                }
+
+                let plugins = Plugins::new()?;
+                let mut js = JS::new(
+                    Some(js_operation_rx),
+                    Some(Arc::clone(&VEC_DEQUE)),
+                    Some(plugins),
+                )?;
+                if safe_mode {
+                    js.enter_safe_mode()?;
+                } else if let Err(err) = js.load_user_scripts() {
+                    log::error!("Cannot load plugin: {:?}", err);
+                }
+                Ok(js)
+            },
+            event_deadline,
         )
-            .expect("Cannot create JS instance");
-        if let Err(err) = js.load_user_scripts() {
-            log::error!("Cannot load plugin: {:?}", err);
-        }
-
-        let result = grab_setup(js);
+        .and_then(|worker| grab_setup(worker, kill_switch, pause_hotkey));
         if let Err(err) = &result {
             log::error!("Cannot run handler: {:?}", err);
         }
-        setup_tx.send(result).expect("Send setup message");
+        if let Err(err) = setup_tx.send(result) {
+            log::error!("Cannot send setup message: {:?}", err);
+        }
         grab_run();
     });
 
     log::debug!("Creating menu object");
 
-    let quit = CustomMenuItem::new("quit".to_string(), "Quit");
-    let configuration = CustomMenuItem::new("configuration".to_string(), "Configuration");
-    let tray_menu = SystemTrayMenu::new()
-        .add_item(configuration)
-        .add_native_item(SystemTrayMenuItem::Separator) // separator
-        .add_item(quit);
-
-    let tray = SystemTray::new().with_menu(tray_menu);
+    let tray = SystemTray::new().with_menu(build_tray_menu(None));
 
     log::debug!("Building tauri");
 
     tauri::Builder::default()
         .plugin(tauri_plugin_positioner::init())
         .setup(move |app| {
+            app.manage(JsOperationSender(js_operation_tx));
+            app.manage(SafeMode(safe_mode));
+
+            let app_handle = app.handle();
             app.listen_global("js-operation", move |event| {
                 // update-config
                 log::info!("js-operation: {:?}", event);
-                let js_operation: JsOperation = serde_json::from_str(event.payload().unwrap())
-                    .expect("Deserialize js-operation");
-                js_operation_tx.send(js_operation)
-                    .expect("Send message");
+                let js_operation: JsOperation =
+                    match serde_json::from_str(event.payload().unwrap_or_default()) {
+                        Ok(js_operation) => js_operation,
+                        Err(err) => {
+                            log::error!("Cannot deserialize js-operation: {:?}", err);
+                            return;
+                        }
+                    };
+                send_js_operation(&app_handle, js_operation);
+                // the plugins or their enabled state may be changed.
+                refresh_tray_menu(&app_handle);
+            });
+
+            let app_handle = app.handle();
+            thread::spawn(move || {
+                for state in passthrough_rx {
+                    update_passthrough_indicator(&app_handle, state);
+                    if let Err(err) = save_pause_state(state.paused) {
+                        log::error!("Cannot save pause state: {:?}", err);
+                    }
+                }
+            });
+
+            let app_handle = app.handle();
+            thread::spawn(move || {
+                for result in js_operation_result_rx {
+                    if result.operation == WORKER_STOPPED {
+                        notify_worker_stopped(&app_handle, &result);
+                    }
+                    if let Err(err) = app_handle.emit_all("js-operation-result", result) {
+                        log::error!("Cannot emit js-operation-result: {:?}", err);
+                    }
+                }
             });
 
             log::info!("Waiting CGEventTapCreate");
-            let setup_result = setup_rx.recv().expect("Setup message received");
+            let setup_result = setup_rx
+                .recv()
+                .unwrap_or_else(|err| Err(anyhow!("Handler thread was terminated: {:?}", err)));
             if let Err(err) = setup_result {
                 log::error!("Cannot run handler: {:?}", err);
                 dialog::message::<Wry>(
@@ -334,6 +836,15 @@ fn main() -> anyhow::Result<()> {
                         err
                     ),
                 );
+            } else {
+                refresh_tray_menu(&app.handle());
+                if safe_mode {
+                    dialog::message::<Wry>(
+                        None,
+                        "KeyScripten",
+                        safe_mode_message(&previous_session),
+                    );
+                }
             }
 
             Ok(())
@@ -346,8 +857,21 @@ fn main() -> anyhow::Result<()> {
             match event {
                 SystemTrayEvent::MenuItemClick { id, .. } => match id.as_str() {
                     "quit" => {
+                        session::end_session();
                         std::process::exit(0);
                     }
+                    "pause" => {
+                        if passthrough::is_passthrough() {
+                            passthrough::resume();
+                        } else {
+                            passthrough::set_paused(true);
+                        }
+                    }
+                    id if id.starts_with(PLUGIN_MENU_PREFIX) => {
+                        if let Err(err) = toggle_plugin(app, &id[PLUGIN_MENU_PREFIX.len()..]) {
+                            log::error!("Cannot toggle plugin: {:?}", err);
+                        }
+                    }
                     "configuration" => {
                         log::info!("Got configuration event");
                         let window_label = "config-window".to_string();
@@ -384,6 +908,11 @@ fn main() -> anyhow::Result<()> {
         .invoke_handler(tauri::generate_handler![
             get_config_schema,
             load_config,
+            get_config_parse_error,
+            list_config_backups,
+            restore_config_backup,
+            list_unknown_plugin_configs,
+            remove_unknown_plugin_configs,
             save_config_for_plugin,
             load_config_for_plugin,
             get_config_schema_for_plugin,
@@ -391,19 +920,26 @@ fn main() -> anyhow::Result<()> {
             get_event_log,
             add_plugin,
             list_plugins,
+            list_plugin_manifests,
             read_plugin_code,
             write_plugin_code,
             delete_plugin,
             read_logs,
             read_console_logs,
             get_plugin_filename,
+            get_plugin_faults,
+            get_insecure_files,
+            fix_insecure_files,
+            get_deadline_misses,
+            update_remember_pause_state,
+            eval_js,
         ])
         .build(tauri::generate_context!())
         .expect("error while building tauri application")
-        .run(|_app_handle, event| {
-            if let tauri::RunEvent::ExitRequested { api, .. } = event {
-                api.prevent_exit();
-            }
+        .run(|_app_handle, event| match event {
+            tauri::RunEvent::ExitRequested { api, .. } => api.prevent_exit(),
+            tauri::RunEvent::Exit => session::end_session(),
+            _ => {}
         });
 
     Ok(())